@@ -0,0 +1,118 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+
+use crate::config::DeployMode;
+use crate::webhook::Provider;
+
+/// Deploys a git repository when GitHub, GitLab, Gitea or Bitbucket reports a push to it.
+///
+/// Flags override the configuration file, which overrides the built-in defaults. Most flags can
+/// also be set through the environment variable shown in their help.
+#[derive(Parser)]
+#[command(version)]
+pub struct Cli {
+    /// TOML configuration file. See config.example.toml.
+    #[arg(long, env = "PULL_SERVER_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Validate the configuration and exit without starting the server.
+    #[arg(long)]
+    pub check_config: bool,
+
+    /// Address to listen on [default: 127.0.0.1]
+    #[arg(long, env = "PULL_SERVER_BIND")]
+    pub bind: Option<String>,
+
+    /// Port to listen on [default: 10000]
+    #[arg(long, env = "PULL_SERVER_PORT")]
+    pub port: Option<u16>,
+
+    /// JSON Lines file recording every finished deploy [default: deploys.jsonl]
+    #[arg(long, env = "PULL_SERVER_HISTORY")]
+    pub history: Option<PathBuf>,
+
+    /// Log filter such as `debug` or `info,pull_server=debug` [default: info]
+    #[arg(long, env = "PULL_SERVER_LOG_LEVEL")]
+    pub log_level: Option<String>,
+
+    /// Directory for the daily deploy-YYYY-MM-DD.log files [default: logs]
+    #[arg(long, env = "PULL_SERVER_LOG_DIR")]
+    pub log_dir: Option<PathBuf>,
+
+    /// Repository to deploy [default: the current directory]
+    #[arg(long, env = "PULL_SERVER_REPO")]
+    pub repo: Option<PathBuf>,
+
+    /// Remote to fetch from [default: origin]
+    #[arg(long, env = "PULL_SERVER_REMOTE")]
+    pub remote: Option<String>,
+
+    /// Branch to deploy; pushes to other branches are skipped [default: dev]
+    #[arg(long, env = "PULL_SERVER_BRANCH")]
+    pub branch: Option<String>,
+
+    /// How the working tree is updated [default: reset]
+    #[arg(long, env = "PULL_SERVER_MODE")]
+    pub mode: Option<DeployMode>,
+
+    /// Forge sending the push webhooks [default: github]
+    #[arg(long, env = "PULL_SERVER_PROVIDER")]
+    pub provider: Option<Provider>,
+
+    /// Environment variable holding the webhook secret [default: PULL_SERVER_SECRET]
+    #[arg(long)]
+    pub secret_env: Option<String>,
+
+    /// Seconds before a git command is killed [default: 120]
+    #[arg(long, env = "PULL_SERVER_GIT_TIMEOUT")]
+    pub git_timeout: Option<u64>,
+
+    /// Seconds from one deploy finishing to the next starting [default: 0]
+    #[arg(long, env = "PULL_SERVER_COOLDOWN")]
+    pub cooldown: Option<u64>,
+
+    /// TOML file with the post-deploy steps, replacing any steps in the configuration file.
+    #[arg(long, env = "PULL_SERVER_PIPELINE")]
+    pub pipeline: Option<PathBuf>,
+
+    /// URL probed after the pipeline; an unhealthy deploy is rolled back.
+    #[arg(long, env = "PULL_SERVER_HEALTH_URL")]
+    pub health_url: Option<String>,
+
+    /// Health probe attempts [default: 10]
+    #[arg(long, env = "PULL_SERVER_HEALTH_RETRIES")]
+    pub health_retries: Option<u32>,
+
+    /// Seconds between health probe attempts [default: 3]
+    #[arg(long, env = "PULL_SERVER_HEALTH_INTERVAL")]
+    pub health_interval: Option<u64>,
+
+    /// Seconds before a health probe attempt times out [default: 5]
+    #[arg(long, env = "PULL_SERVER_HEALTH_TIMEOUT")]
+    pub health_timeout: Option<u64>,
+
+    /// Accept invalid TLS certificates from the health URL [default: false]
+    #[arg(long, env = "PULL_SERVER_HEALTH_INSECURE")]
+    pub health_insecure: Option<bool>,
+}
+
+impl Cli {
+    /// Whether any flag describing the [`crate::config::DEFAULT_TARGET`] was given.
+    pub fn overrides_target(&self) -> bool {
+        self.repo.is_some()
+            || self.remote.is_some()
+            || self.branch.is_some()
+            || self.mode.is_some()
+            || self.provider.is_some()
+            || self.secret_env.is_some()
+            || self.git_timeout.is_some()
+            || self.cooldown.is_some()
+            || self.pipeline.is_some()
+            || self.health_url.is_some()
+            || self.health_retries.is_some()
+            || self.health_interval.is_some()
+            || self.health_timeout.is_some()
+            || self.health_insecure.is_some()
+    }
+}
@@ -0,0 +1,1152 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::ValueEnum;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+use tracing_subscriber::EnvFilter;
+
+use crate::cli::Cli;
+use crate::error::Error;
+use crate::git;
+use crate::webhook::Provider;
+
+/// How the working tree is brought up to date when a push arrives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DeployMode {
+    /// `git pull` whatever the upstream branch points at. Workflow runs still reset to the commit
+    /// that passed.
+    Pull,
+    /// Fetch, then check out the pushed commit as a detached HEAD.
+    Checkout,
+    /// Fetch, then `git reset --hard` the current branch to the pushed commit.
+    Reset,
+}
+
+impl DeployMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            DeployMode::Pull => "pull",
+            DeployMode::Checkout => "checkout",
+            DeployMode::Reset => "reset",
+        }
+    }
+}
+
+/// One post-deploy command, run through `sh -c` once the working tree has been updated.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Step {
+    pub name: String,
+    pub command: String,
+    /// Working directory, relative to the repository root. Defaults to the root itself.
+    pub dir: Option<PathBuf>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default = "default_step_timeout", deserialize_with = "seconds")]
+    pub timeout: Duration,
+    /// Only run when the deploy changed a file matching one of these globs. Runs every time if unset.
+    pub paths: Option<PathFilter>,
+}
+
+/// Glob patterns such as `package.json` or `src/**`, matched against repository-relative paths.
+/// `*` does not cross directory separators; `**` does.
+#[derive(Debug)]
+pub struct PathFilter {
+    pub patterns: Vec<String>,
+    set: GlobSet,
+}
+
+impl PathFilter {
+    pub fn matches_any(&self, files: &[String]) -> bool {
+        files.iter().any(|file| self.set.is_match(file))
+    }
+}
+
+impl<'de> Deserialize<'de> for PathFilter {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let patterns = Vec::<String>::deserialize(deserializer)?;
+        let mut builder = GlobSetBuilder::new();
+        for pattern in &patterns {
+            let glob = GlobBuilder::new(pattern)
+                .literal_separator(true)
+                .build()
+                .map_err(D::Error::custom)?;
+            builder.add(glob);
+        }
+        let set = builder.build().map_err(D::Error::custom)?;
+        Ok(PathFilter { patterns, set })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Pipeline {
+    #[serde(default)]
+    steps: Vec<Step>,
+}
+
+fn default_step_timeout() -> Duration {
+    Duration::from_secs(600)
+}
+
+fn seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
+
+/// HTTP probe run after the pipeline to confirm the backend came back up.
+pub struct HealthCheck {
+    pub url: String,
+    /// Attempts before the deploy is declared unhealthy.
+    pub retries: u32,
+    /// Pause between attempts.
+    pub interval: Duration,
+    /// Per-request timeout.
+    pub timeout: Duration,
+    /// Accept invalid TLS certificates, e.g. the backend's self-signed cert in keys/.
+    pub insecure: bool,
+}
+
+/// Message format of a notification webhook.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SinkKind {
+    /// Slack incoming webhooks and compatible chats such as Mattermost: `{"text": ...}`.
+    Slack,
+    /// Discord webhooks: `{"content": ...}`.
+    Discord,
+    /// The notice itself as a JSON object, for other services.
+    Json,
+}
+
+impl SinkKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            SinkKind::Slack => "slack",
+            SinkKind::Discord => "discord",
+            SinkKind::Json => "json",
+        }
+    }
+}
+
+/// Deploy events a notification sink can subscribe to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyEvent {
+    Started,
+    Succeeded,
+    Failed,
+    Cancelled,
+    /// The deploy failed its health check and the previous commit was restored.
+    RolledBack,
+}
+
+const ALL_EVENTS: &[NotifyEvent] = &[
+    NotifyEvent::Started,
+    NotifyEvent::Succeeded,
+    NotifyEvent::Failed,
+    NotifyEvent::Cancelled,
+    NotifyEvent::RolledBack,
+];
+
+/// A webhook told about deploys, from a `[[notify]]` table.
+pub struct Sink {
+    pub kind: SinkKind,
+    pub url: String,
+    /// Targets this sink hears about; every target when empty.
+    pub targets: Vec<String>,
+    pub events: Vec<NotifyEvent>,
+    /// Further attempts after a failed delivery, with exponential backoff.
+    pub retries: u32,
+}
+
+impl Sink {
+    pub fn wants(&self, target: &str, event: NotifyEvent) -> bool {
+        (self.targets.is_empty() || self.targets.iter().any(|name| name == target)) && self.events.contains(&event)
+    }
+}
+
+/// Credentials for reporting deploys to GitHub, needed once a target sets `github_repository`.
+pub struct GitHubConfig {
+    /// REST API root, e.g. `https://github.example.com/api/v3` for GitHub Enterprise.
+    pub api_url: String,
+    pub token: String,
+    /// Where this server is reachable, for the job log links on GitHub.
+    pub public_url: Option<String>,
+}
+
+/// Where a target's deploys are recorded on GitHub.
+pub struct GitHubTarget {
+    /// `owner/name` of the deployed repository.
+    pub repository: String,
+    pub environment: String,
+}
+
+pub struct Config {
+    pub bind: String,
+    pub port: u16,
+    /// Append-only deploy history, see [`crate::history::History`].
+    pub history: PathBuf,
+    pub log: LogConfig,
+    pub replay: ReplayConfig,
+    pub rate_limit: RateLimitConfig,
+    pub notify: Vec<Arc<Sink>>,
+    /// Set when any target reports to GitHub.
+    pub github: Option<GitHubConfig>,
+    /// Deploy targets by name; each gets its own queue.
+    pub targets: BTreeMap<String, Arc<Target>>,
+}
+
+pub struct LogConfig {
+    /// `tracing` filter directives, e.g. `info` or `info,pull_server=debug`.
+    pub level: String,
+    /// Where the daily log files go. The backend's `logs/` puts them in the admin log viewer.
+    pub dir: PathBuf,
+    /// Daily files kept before the oldest is deleted.
+    pub keep_days: usize,
+}
+
+/// How webhook replays are detected, see [`crate::replay::ReplayGuard`].
+pub struct ReplayConfig {
+    /// Recently seen delivery IDs, one per line.
+    pub store: PathBuf,
+    /// Delivery IDs remembered.
+    pub capacity: usize,
+    /// Payloads sent longer ago than this are refused. `None` accepts any age.
+    pub max_age: Option<Duration>,
+}
+
+/// Token buckets refusing deploy requests beyond a steady rate, see [`crate::limit::RateLimiter`].
+pub struct RateLimitConfig {
+    /// Per client IP, across all targets.
+    pub client: BucketConfig,
+    /// Per target, across all clients.
+    pub target: BucketConfig,
+    /// Take the client IP from `Forwarded` or `X-Forwarded-For`, for use behind a reverse proxy.
+    pub trust_proxy: bool,
+}
+
+pub struct BucketConfig {
+    /// Requests allowed at once. 0 turns the limit off.
+    pub burst: u32,
+    /// Requests regained per minute, up to `burst`.
+    pub per_minute: u32,
+}
+
+/// One deployable checkout, served at `POST /deploy/{name}`.
+pub struct Target {
+    pub name: String,
+    /// Working tree of the deployed repository.
+    pub repo: PathBuf,
+    /// `.git` directory of `repo`, resolved at startup.
+    pub git_dir: PathBuf,
+    pub secret: Vec<u8>,
+    /// Forge sending the push webhooks.
+    pub provider: Provider,
+    /// Branch this target deploys; pushes to any other ref are skipped.
+    pub branch: String,
+    pub remote: String,
+    pub mode: DeployMode,
+    /// Upper bound on every git invocation; hung processes are killed after this.
+    pub git_timeout: Duration,
+    /// Least time from one deploy finishing to the next starting; zero allows them back to back.
+    pub cooldown: Duration,
+    /// Post-deploy steps, run in order; the first failure aborts the rest.
+    pub steps: Vec<Step>,
+    /// When set, an unhealthy deploy is rolled back to the previous commit.
+    pub health: Option<HealthCheck>,
+    /// GitHub workflows, by name or file path, whose successful runs on `branch` deploy. Runs of
+    /// any other workflow are skipped.
+    pub workflows: Vec<String>,
+    /// Whether published GitHub releases deploy their tag, whatever branch it was cut from.
+    pub releases: bool,
+    /// Whether prereleases deploy too, when `releases` is set.
+    pub prereleases: bool,
+    pub github: Option<GitHubTarget>,
+}
+
+/// Name of the target configured by the top-level `[repository]` section and the command-line
+/// flags. `POST /` deploys it.
+pub const DEFAULT_TARGET: &str = "default";
+
+/// Layout of the `--config` file. Every key is optional; see config.example.toml.
+///
+/// Either `[targets.NAME]` tables declare the targets, or the top-level keys describe a single
+/// target named [`DEFAULT_TARGET`].
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct File {
+    server: ServerSection,
+    log: LogSection,
+    replay: ReplaySection,
+    rate_limit: RateLimitSection,
+    github: GitHubSection,
+    repository: RepositorySection,
+    health: Option<HealthSection>,
+    pipeline: Option<PathBuf>,
+    steps: Vec<Step>,
+    targets: BTreeMap<String, TargetSection>,
+    notify: Vec<NotifySection>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ServerSection {
+    bind: Option<String>,
+    port: Option<u16>,
+    history: Option<PathBuf>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LogSection {
+    level: Option<String>,
+    dir: Option<PathBuf>,
+    keep_days: Option<usize>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ReplaySection {
+    store: Option<PathBuf>,
+    capacity: Option<usize>,
+    max_age: Option<u64>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RateLimitSection {
+    client_burst: Option<u32>,
+    client_per_minute: Option<u32>,
+    target_burst: Option<u32>,
+    target_per_minute: Option<u32>,
+    trust_proxy: Option<bool>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct GitHubSection {
+    api_url: Option<String>,
+    token_env: Option<String>,
+    public_url: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RepositorySection {
+    path: Option<PathBuf>,
+    remote: Option<String>,
+    branch: Option<String>,
+    mode: Option<DeployMode>,
+    provider: Option<Provider>,
+    secret_env: Option<String>,
+    git_timeout: Option<u64>,
+    cooldown: Option<u64>,
+    workflows: Vec<String>,
+    releases: Option<bool>,
+    prereleases: Option<bool>,
+    github_repository: Option<String>,
+    environment: Option<String>,
+}
+
+/// A `[targets.NAME]` table: the `[repository]` keys plus the target's own pipeline and health check.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TargetSection {
+    path: Option<PathBuf>,
+    remote: Option<String>,
+    branch: Option<String>,
+    mode: Option<DeployMode>,
+    provider: Option<Provider>,
+    /// Name of the environment variable holding the webhook secret, so the file itself can be
+    /// committed.
+    secret_env: Option<String>,
+    git_timeout: Option<u64>,
+    /// Seconds from one deploy finishing to the next starting.
+    cooldown: Option<u64>,
+    /// Names or file paths of the GitHub workflows whose successful runs deploy.
+    workflows: Vec<String>,
+    /// Whether published GitHub releases deploy their tag.
+    releases: Option<bool>,
+    /// Whether prereleases deploy too.
+    prereleases: Option<bool>,
+    /// `owner/name` on GitHub; when set, deploys are reported as GitHub Deployments.
+    github_repository: Option<String>,
+    /// GitHub deployment environment. Defaults to the target name.
+    environment: Option<String>,
+    /// Separate pipeline file, as an alternative to inline `steps`.
+    pipeline: Option<PathBuf>,
+    steps: Vec<Step>,
+    health: Option<HealthSection>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NotifySection {
+    kind: SinkKind,
+    url: Option<String>,
+    /// Name of the environment variable holding the URL, since webhook URLs embed their token.
+    url_env: Option<String>,
+    #[serde(default)]
+    targets: Vec<String>,
+    events: Option<Vec<NotifyEvent>>,
+    retries: Option<u32>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct HealthSection {
+    url: Option<String>,
+    retries: Option<u32>,
+    interval: Option<u64>,
+    timeout: Option<u64>,
+    insecure: Option<bool>,
+}
+
+impl Config {
+    /// Merges `cli` over the `--config` file over the defaults and validates the result.
+    pub fn load(cli: &Cli) -> Result<Self, Error> {
+        let (file, base) = match &cli.config {
+            Some(path) => (read_file(path)?, path.parent().unwrap_or(Path::new("")).to_path_buf()),
+            None => (File::default(), PathBuf::new()),
+        };
+
+        let mut sections = file.targets;
+        if sections.is_empty() {
+            let mut section = TargetSection {
+                path: file.repository.path,
+                remote: file.repository.remote,
+                branch: file.repository.branch,
+                mode: file.repository.mode,
+                provider: file.repository.provider,
+                secret_env: file.repository.secret_env,
+                git_timeout: file.repository.git_timeout,
+                cooldown: file.repository.cooldown,
+                workflows: file.repository.workflows,
+                releases: file.repository.releases,
+                prereleases: file.repository.prereleases,
+                github_repository: file.repository.github_repository,
+                environment: file.repository.environment,
+                pipeline: file.pipeline,
+                steps: file.steps,
+                health: file.health,
+            };
+            // Paths in the file are relative to the file; paths on the command line to the
+            // working directory.
+            section.resolve(&base);
+            section.override_with(cli);
+            sections.insert(DEFAULT_TARGET.to_string(), section);
+        } else if file.repository.path.is_some()
+            || file.repository.github_repository.is_some()
+            || file.pipeline.is_some()
+            || !file.steps.is_empty()
+            || file.health.is_some()
+        {
+            return Err(Error::Config(
+                "declare the repository, pipeline, steps and health inside each [targets.NAME] \
+                 table when [targets] is used"
+                    .to_string(),
+            ));
+        } else if cli.overrides_target() {
+            return Err(Error::Config(
+                "repository, pipeline and health flags only apply when the config declares no [targets]"
+                    .to_string(),
+            ));
+        } else {
+            for section in sections.values_mut() {
+                section.resolve(&base);
+            }
+        }
+
+        let mut targets = BTreeMap::new();
+        for (name, section) in sections {
+            let target = section
+                .build(&name)
+                .map_err(|err| Error::Config(format!("target {}: {}", name, config_message(err))))?;
+            targets.insert(name, Arc::new(target));
+        }
+
+        let notify = file
+            .notify
+            .into_iter()
+            .enumerate()
+            .map(|(index, section)| {
+                section
+                    .build()
+                    .map(Arc::new)
+                    .map_err(|err| Error::Config(format!("notify #{}: {}", index + 1, config_message(err))))
+            })
+            .collect::<Result<_, _>>()?;
+
+        let github = match targets.values().any(|target| target.github.is_some()) {
+            true => Some(file.github.build()?),
+            false => None,
+        };
+
+        let config = Config {
+            bind: cli.bind.clone().or(file.server.bind).unwrap_or_else(|| "127.0.0.1".to_string()),
+            port: cli.port.or(file.server.port).unwrap_or(10000),
+            history: match (&cli.history, file.server.history) {
+                (Some(history), _) => history.clone(),
+                (None, Some(history)) => base.join(history),
+                (None, None) => PathBuf::from("deploys.jsonl"),
+            },
+            log: LogConfig {
+                level: cli.log_level.clone().or(file.log.level).unwrap_or_else(|| "info".to_string()),
+                dir: match (&cli.log_dir, file.log.dir) {
+                    (Some(dir), _) => dir.clone(),
+                    (None, Some(dir)) => base.join(dir),
+                    (None, None) => PathBuf::from("logs"),
+                },
+                keep_days: file.log.keep_days.unwrap_or(14),
+            },
+            replay: ReplayConfig {
+                store: match file.replay.store {
+                    Some(store) => base.join(store),
+                    None => PathBuf::from("deliveries.txt"),
+                },
+                capacity: file.replay.capacity.unwrap_or(10000),
+                max_age: match file.replay.max_age.unwrap_or(3600) {
+                    0 => None,
+                    seconds => Some(Duration::from_secs(seconds)),
+                },
+            },
+            rate_limit: RateLimitConfig {
+                client: BucketConfig {
+                    burst: file.rate_limit.client_burst.unwrap_or(30),
+                    per_minute: file.rate_limit.client_per_minute.unwrap_or(30),
+                },
+                target: BucketConfig {
+                    burst: file.rate_limit.target_burst.unwrap_or(10),
+                    per_minute: file.rate_limit.target_per_minute.unwrap_or(6),
+                },
+                trust_proxy: file.rate_limit.trust_proxy.unwrap_or(false),
+            },
+            notify,
+            github,
+            targets,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Catches mistakes that would otherwise only surface on the first deploy.
+    fn validate(&self) -> Result<(), Error> {
+        let mut problems = Vec::new();
+        if self.port == 0 {
+            problems.push("server.port must not be 0".to_string());
+        }
+        if let Err(err) = EnvFilter::try_new(&self.log.level) {
+            problems.push(format!("log.level {:?} is invalid: {}", self.log.level, err));
+        }
+        if self.log.keep_days == 0 {
+            problems.push("log.keep_days must be at least 1".to_string());
+        }
+        if self.replay.capacity == 0 {
+            problems.push("replay.capacity must be at least 1".to_string());
+        }
+        for (name, bucket) in [("client", &self.rate_limit.client), ("target", &self.rate_limit.target)] {
+            if bucket.burst > 0 && bucket.per_minute == 0 {
+                problems.push(format!("rate_limit.{}_per_minute must be at least 1", name));
+            }
+        }
+        if let Some(github) = &self.github {
+            if let Err(err) = reqwest::Url::parse(&github.api_url) {
+                problems.push(format!("github.api_url {:?} is invalid: {}", github.api_url, err));
+            }
+            if let Some(url) = &github.public_url
+                && let Err(err) = reqwest::Url::parse(url)
+            {
+                problems.push(format!("github.public_url {:?} is invalid: {}", url, err));
+            }
+        }
+        for (index, sink) in self.notify.iter().enumerate() {
+            if let Err(err) = reqwest::Url::parse(&sink.url) {
+                problems.push(format!("notify #{}: url is invalid: {}", index + 1, err));
+            }
+            if sink.events.is_empty() {
+                problems.push(format!("notify #{}: events must not be empty", index + 1));
+            }
+            for name in sink.targets.iter().filter(|name| !self.targets.contains_key(*name)) {
+                problems.push(format!("notify #{}: there is no target {}", index + 1, name));
+            }
+        }
+        let mut repos = HashMap::new();
+        for target in self.targets.values() {
+            if let Some(other) = repos.insert(&target.repo, &target.name) {
+                problems.push(format!(
+                    "targets {} and {} share the working tree {}",
+                    other,
+                    target.name,
+                    target.repo.display()
+                ));
+            }
+            problems.extend(
+                target
+                    .problems()
+                    .into_iter()
+                    .map(|problem| format!("target {}: {}", target.name, problem)),
+            );
+        }
+
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(Error::Config(problems.join("; ")))
+    }
+
+    /// Human-readable summary printed by `--check-config`.
+    pub fn describe(&self) -> String {
+        let mut lines = vec![
+            format!("listen:  {}:{}", self.bind, self.port),
+            format!("history: {}", self.history.display()),
+            format!(
+                "logs:    {}/deploy-YYYY-MM-DD.log at {} ({} days kept)",
+                self.log.dir.display(),
+                self.log.level,
+                self.log.keep_days
+            ),
+            format!(
+                "replay:  last {} deliveries in {}, {}",
+                self.replay.capacity,
+                self.replay.store.display(),
+                match self.replay.max_age {
+                    Some(max_age) => format!("payloads older than {}s refused", max_age.as_secs()),
+                    None => "payloads of any age accepted".to_string(),
+                }
+            ),
+            format!(
+                "limits:  per client {}, per target {}{}",
+                self.rate_limit.client.describe(),
+                self.rate_limit.target.describe(),
+                if self.rate_limit.trust_proxy { ", client IP from proxy headers" } else { "" }
+            ),
+        ];
+        for target in self.targets.values() {
+            lines.push(format!("target {}:", target.name));
+            lines.push(format!("  repository: {}", target.repo.display()));
+            lines.push(format!(
+                "  branch:     {} from {} ({} mode)",
+                target.branch,
+                target.remote,
+                target.mode.name()
+            ));
+            lines.push(format!("  webhooks:   {}", target.provider.name()));
+            lines.push(format!("  git:        {}s timeout", target.git_timeout.as_secs()));
+            lines.push(match target.cooldown.as_secs() {
+                0 => "  cooldown:   none".to_string(),
+                seconds => format!("  cooldown:   {}s between deploys", seconds),
+            });
+            let steps: Vec<&str> = target.steps.iter().map(|step| step.name.as_str()).collect();
+            lines.push(format!(
+                "  pipeline:   {}",
+                if steps.is_empty() { "none".to_string() } else { steps.join(", ") }
+            ));
+            lines.push(match &target.health {
+                Some(health) => format!("  health:     {} ({} attempts)", health.url, health.retries),
+                None => "  health:     none".to_string(),
+            });
+            if !target.workflows.is_empty() {
+                lines.push(format!("  workflows:  {}", target.workflows.join(", ")));
+            }
+            if target.releases {
+                lines.push(format!(
+                    "  releases:   published{}",
+                    if target.prereleases { " and prereleases" } else { ", prereleases skipped" }
+                ));
+            }
+            if let Some(github) = &target.github {
+                lines.push(format!("  github:     {} ({} environment)", github.repository, github.environment));
+            }
+        }
+        for sink in &self.notify {
+            let host = reqwest::Url::parse(&sink.url)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_string))
+                .unwrap_or_default();
+            lines.push(format!(
+                "notify {} at {} for {}",
+                sink.kind.name(),
+                host,
+                if sink.targets.is_empty() { "all targets".to_string() } else { sink.targets.join(", ") }
+            ));
+        }
+        lines.join("\n")
+    }
+}
+
+impl Target {
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.name.is_empty()
+            || !self
+                .name
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+        {
+            problems.push("name may only contain letters, digits, - and _".to_string());
+        }
+        if self.branch.is_empty() {
+            problems.push("branch must not be empty".to_string());
+        }
+        if self.remote.is_empty() {
+            problems.push("remote must not be empty".to_string());
+        }
+        if self.git_timeout.is_zero() {
+            problems.push("git_timeout must be at least 1 second".to_string());
+        }
+        let mut names = HashSet::new();
+        for step in &self.steps {
+            if step.name.is_empty() {
+                problems.push(format!("step {:?} needs a name", step.command));
+            } else if !names.insert(step.name.as_str()) {
+                problems.push(format!("step name {} is used more than once", step.name));
+            }
+            if step.command.trim().is_empty() {
+                problems.push(format!("step {} has an empty command", step.name));
+            }
+            if step.timeout.is_zero() {
+                problems.push(format!("step {} timeout must be at least 1 second", step.name));
+            }
+            if let Some(dir) = &step.dir
+                && !self.repo.join(dir).is_dir()
+            {
+                problems.push(format!("step {} dir {} does not exist", step.name, self.repo.join(dir).display()));
+            }
+        }
+        if let Some(github) = &self.github
+            && github.repository.split('/').filter(|part| !part.is_empty()).count() != 2
+        {
+            problems.push(format!("github_repository {:?} must be owner/name", github.repository));
+        }
+        if let Some(health) = &self.health {
+            if let Err(err) = reqwest::Url::parse(&health.url) {
+                problems.push(format!("health.url {:?} is invalid: {}", health.url, err));
+            }
+            if health.timeout.is_zero() {
+                problems.push("health.timeout must be at least 1 second".to_string());
+            }
+        }
+        problems
+    }
+}
+
+impl TargetSection {
+    fn resolve(&mut self, base: &Path) {
+        self.path = self.path.as_ref().map(|path| base.join(path));
+        self.pipeline = self.pipeline.as_ref().map(|path| base.join(path));
+    }
+
+    /// Applies the command-line flags, which only exist for the [`DEFAULT_TARGET`].
+    fn override_with(&mut self, cli: &Cli) {
+        fn set<T: Clone>(field: &mut Option<T>, value: &Option<T>) {
+            if value.is_some() {
+                field.clone_from(value);
+            }
+        }
+        set(&mut self.path, &cli.repo);
+        set(&mut self.remote, &cli.remote);
+        set(&mut self.branch, &cli.branch);
+        set(&mut self.mode, &cli.mode);
+        set(&mut self.provider, &cli.provider);
+        set(&mut self.secret_env, &cli.secret_env);
+        set(&mut self.git_timeout, &cli.git_timeout);
+        set(&mut self.cooldown, &cli.cooldown);
+        if cli.pipeline.is_some() {
+            self.pipeline.clone_from(&cli.pipeline);
+            self.steps.clear();
+        }
+        let from_file = self.health.is_some();
+        let health = self.health.get_or_insert_with(HealthSection::default);
+        set(&mut health.url, &cli.health_url);
+        set(&mut health.retries, &cli.health_retries);
+        set(&mut health.interval, &cli.health_interval);
+        set(&mut health.timeout, &cli.health_timeout);
+        set(&mut health.insecure, &cli.health_insecure);
+        if health.url.is_none() && !from_file {
+            self.health = None;
+        }
+    }
+
+    fn build(self, name: &str) -> Result<Target, Error> {
+        let repo = self.path.unwrap_or_else(|| PathBuf::from("."));
+        let repo = fs::canonicalize(&repo)
+            .map_err(|err| Error::Config(format!("repository path {}: {}", repo.display(), err)))?;
+
+        let secret_env = self.secret_env.unwrap_or_else(|| "PULL_SERVER_SECRET".to_string());
+        let secret = env::var(&secret_env)
+            .ok()
+            .filter(|secret| !secret.is_empty())
+            .ok_or_else(|| Error::Config(format!("the webhook secret variable {} must be set", secret_env)))?;
+
+        let steps = match self.pipeline {
+            Some(_) if !self.steps.is_empty() => {
+                return Err(Error::Config("set either pipeline or [[steps]], not both".to_string()));
+            }
+            Some(path) => load_pipeline(&path)?,
+            None => self.steps,
+        };
+
+        let health = match self.health {
+            Some(HealthSection { url: None, .. }) => {
+                return Err(Error::Config("health.url is required in [health]".to_string()));
+            }
+            Some(HealthSection {
+                url: Some(url),
+                retries,
+                interval,
+                timeout,
+                insecure,
+            }) => Some(HealthCheck {
+                url,
+                retries: retries.unwrap_or(10),
+                interval: Duration::from_secs(interval.unwrap_or(3)),
+                timeout: Duration::from_secs(timeout.unwrap_or(5)),
+                insecure: insecure.unwrap_or(false),
+            }),
+            None => None,
+        };
+
+        Ok(Target {
+            name: name.to_string(),
+            git_dir: git::find_git_dir(&repo)?,
+            repo,
+            secret: secret.into_bytes(),
+            provider: self.provider.unwrap_or(Provider::Github),
+            branch: self.branch.unwrap_or_else(|| "dev".to_string()),
+            remote: self.remote.unwrap_or_else(|| "origin".to_string()),
+            mode: self.mode.unwrap_or(DeployMode::Reset),
+            git_timeout: Duration::from_secs(self.git_timeout.unwrap_or(120)),
+            cooldown: Duration::from_secs(self.cooldown.unwrap_or(0)),
+            steps,
+            health,
+            workflows: self.workflows,
+            releases: self.releases.unwrap_or(false),
+            prereleases: self.prereleases.unwrap_or(false),
+            github: self.github_repository.map(|repository| GitHubTarget {
+                repository,
+                environment: self.environment.unwrap_or_else(|| name.to_string()),
+            }),
+        })
+    }
+}
+
+impl BucketConfig {
+    fn describe(&self) -> String {
+        match self.burst {
+            0 => "unlimited".to_string(),
+            burst => format!("{} at once then {}/min", burst, self.per_minute),
+        }
+    }
+}
+
+impl GitHubSection {
+    fn build(self) -> Result<GitHubConfig, Error> {
+        let token_env = self.token_env.unwrap_or_else(|| "GITHUB_TOKEN".to_string());
+        let token = env::var(&token_env)
+            .ok()
+            .filter(|token| !token.is_empty())
+            .ok_or_else(|| Error::Config(format!("the GitHub token variable {} must be set", token_env)))?;
+        Ok(GitHubConfig {
+            api_url: self.api_url.unwrap_or_else(|| "https://api.github.com".to_string()),
+            token,
+            public_url: self.public_url,
+        })
+    }
+}
+
+impl NotifySection {
+    fn build(self) -> Result<Sink, Error> {
+        let url = match (self.url, self.url_env) {
+            (Some(_), Some(_)) => return Err(Error::Config("set either url or url_env, not both".to_string())),
+            (Some(url), None) => url,
+            (None, Some(name)) => env::var(&name)
+                .ok()
+                .filter(|url| !url.is_empty())
+                .ok_or_else(|| Error::Config(format!("the webhook URL variable {} must be set", name)))?,
+            (None, None) => return Err(Error::Config("url or url_env is required".to_string())),
+        };
+        Ok(Sink {
+            kind: self.kind,
+            url,
+            targets: self.targets,
+            events: self.events.unwrap_or_else(|| ALL_EVENTS.to_vec()),
+            retries: self.retries.unwrap_or(3),
+        })
+    }
+}
+
+/// The message of a config error without the "Invalid configuration" prefix, for nesting.
+fn config_message(err: Error) -> String {
+    match err {
+        Error::Config(message) => message,
+        err => err.to_string(),
+    }
+}
+
+fn read_file(path: &Path) -> Result<File, Error> {
+    let contents = fs::read_to_string(path)
+        .map_err(|err| Error::Config(format!("Failed to read {}: {}", path.display(), err)))?;
+    toml::from_str(&contents).map_err(|err| Error::Config(format!("Invalid config {}: {}", path.display(), err)))
+}
+
+fn load_pipeline(path: &Path) -> Result<Vec<Step>, Error> {
+    let contents = fs::read_to_string(path)
+        .map_err(|err| Error::Config(format!("Failed to read pipeline {}: {}", path.display(), err)))?;
+    let pipeline: Pipeline = toml::from_str(&contents)
+        .map_err(|err| Error::Config(format!("Invalid pipeline {}: {}", path.display(), err)))?;
+    Ok(pipeline.steps)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    /// Every test process has it, and setting a variable of our own is unsafe with tests on threads.
+    const SECRET_ENV: &str = "PATH";
+
+    /// A directory holding `config.toml` and git working trees `a` and `b`.
+    fn setup(config: &str) -> TempDir {
+        let dir = TempDir::new();
+        for repo in ["a", "b"] {
+            let status = std::process::Command::new("git")
+                .args(["init", "-q", repo])
+                .current_dir(dir.path())
+                .status()
+                .unwrap();
+            assert!(status.success());
+        }
+        fs::write(dir.path().join("config.toml"), config).unwrap();
+        dir
+    }
+
+    fn load(dir: &TempDir, flags: &[&str]) -> Result<Config, String> {
+        let config = dir.path().join("config.toml");
+        let mut args = vec!["pull-server", "--config", config.to_str().unwrap()];
+        args.extend(flags);
+        Config::load(&Cli::parse_from(args)).map_err(config_message)
+    }
+
+    fn error(dir: &TempDir, flags: &[&str]) -> String {
+        match load(dir, flags) {
+            Ok(_) => panic!("The configuration loaded"),
+            Err(err) => err,
+        }
+    }
+
+    fn target(config: &Config) -> &Target {
+        &config.targets[DEFAULT_TARGET]
+    }
+
+    #[test]
+    fn flags_override_the_file_which_overrides_defaults() {
+        let dir = setup(
+            r#"
+            [server]
+            bind = "0.0.0.0"
+            port = 9000
+            history = "history.jsonl"
+
+            [repository]
+            path = "a"
+            branch = "main"
+            git_timeout = 30
+            secret_env = "PATH"
+            "#,
+        );
+        let config = load(&dir, &["--port", "9100", "--remote", "upstream"]).unwrap();
+        assert_eq!(config.port, 9100);
+        assert_eq!(config.bind, "0.0.0.0");
+        assert_eq!(config.history, dir.path().join("history.jsonl"));
+        assert_eq!(config.log.dir, PathBuf::from("logs"));
+        assert_eq!(config.replay.max_age, Some(Duration::from_secs(3600)));
+
+        let target = target(&config);
+        assert_eq!(target.repo, fs::canonicalize(dir.path().join("a")).unwrap());
+        assert_eq!(target.branch, "main");
+        assert_eq!(target.remote, "upstream");
+        assert_eq!(target.git_timeout, Duration::from_secs(30));
+        assert_eq!(target.mode, DeployMode::Reset);
+        assert_eq!(target.provider, Provider::Github);
+        assert_eq!(target.secret, env::var(SECRET_ENV).unwrap().into_bytes());
+        assert!(target.health.is_none());
+        assert!(!target.releases);
+    }
+
+    #[test]
+    fn resolves_flag_paths_against_the_working_directory() {
+        let dir = setup("[repository]\nsecret_env = \"PATH\"\n");
+        let repo = dir.path().join("b");
+        let config = load(&dir, &["--repo", repo.to_str().unwrap(), "--history", "h.jsonl"]).unwrap();
+        assert_eq!(target(&config).repo, fs::canonicalize(repo).unwrap());
+        assert_eq!(config.history, PathBuf::from("h.jsonl"));
+    }
+
+    #[test]
+    fn keeps_targets_and_the_repository_section_apart() {
+        let targets = "[targets.web]\npath = \"a\"\nsecret_env = \"PATH\"\n";
+        let dir = setup(&format!("[repository]\npath = \"b\"\n\n{}", targets));
+        let err = error(&dir, &[]);
+        assert!(err.contains("inside each [targets.NAME]"), "{}", err);
+
+        let dir = setup(&format!("[health]\nurl = \"http://localhost/health\"\n\n{}", targets));
+        assert!(error(&dir, &[]).contains("inside each [targets.NAME]"));
+
+        let dir = setup(targets);
+        let err = error(&dir, &["--branch", "main"]);
+        assert!(err.contains("flags only apply when the config declares no [targets]"), "{}", err);
+        let config = load(&dir, &["--port", "9100"]).unwrap();
+        assert_eq!(config.targets.keys().collect::<Vec<_>>(), ["web"]);
+    }
+
+    #[test]
+    fn requires_the_secret_variable() {
+        let dir = setup("[repository]\npath = \"a\"\nsecret_env = \"PULL_SERVER_TEST_UNSET\"\n");
+        assert_eq!(
+            error(&dir, &[]),
+            "target default: the webhook secret variable PULL_SERVER_TEST_UNSET must be set"
+        );
+    }
+
+    #[test]
+    fn refuses_targets_sharing_a_working_tree() {
+        let dir = setup(
+            r#"
+            [targets.web]
+            path = "a"
+            secret_env = "PATH"
+
+            [targets.api]
+            path = "a/."
+            secret_env = "PATH"
+
+            [targets.docs]
+            path = "b"
+            secret_env = "PATH"
+            "#,
+        );
+        let err = error(&dir, &[]);
+        assert!(err.contains("targets api and web share the working tree"), "{}", err);
+        assert!(!err.contains("docs"), "{}", err);
+    }
+
+    #[test]
+    fn merges_health_flags_into_the_file_section() {
+        let dir = setup(
+            r#"
+            [repository]
+            path = "a"
+            secret_env = "PATH"
+
+            [health]
+            url = "http://localhost:3000/health"
+            retries = 5
+            "#,
+        );
+        let config = load(&dir, &[]).unwrap();
+        let health = target(&config).health.as_ref().unwrap();
+        assert_eq!((health.url.as_str(), health.retries), ("http://localhost:3000/health", 5));
+        assert_eq!(health.interval, Duration::from_secs(3));
+
+        let config = load(&dir, &["--health-url", "http://localhost:4000/up", "--health-interval", "1"]).unwrap();
+        let health = target(&config).health.as_ref().unwrap();
+        assert_eq!((health.url.as_str(), health.retries), ("http://localhost:4000/up", 5));
+        assert_eq!(health.interval, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn needs_a_health_url_from_the_file_or_the_flags() {
+        let dir = setup("[repository]\npath = \"a\"\nsecret_env = \"PATH\"\n");
+        // Tuning flags alone do not turn the check on.
+        let config = load(&dir, &["--health-retries", "2"]).unwrap();
+        assert!(target(&config).health.is_none());
+        let config = load(&dir, &["--health-url", "http://localhost/health", "--health-retries", "2"]).unwrap();
+        assert_eq!(target(&config).health.as_ref().unwrap().retries, 2);
+
+        // A [health] section in the file must say where to probe, even if a flag adds nothing else.
+        let dir = setup("[repository]\npath = \"a\"\nsecret_env = \"PATH\"\n\n[health]\nretries = 5\n");
+        assert_eq!(error(&dir, &[]), "target default: health.url is required in [health]");
+        let config = load(&dir, &["--health-url", "http://localhost/health"]).unwrap();
+        assert_eq!(target(&config).health.as_ref().unwrap().retries, 5);
+    }
+
+    #[test]
+    fn collects_every_problem() {
+        let dir = setup(
+            r#"
+            [server]
+            port = 0
+
+            [replay]
+            capacity = 0
+
+            [rate_limit]
+            client_per_minute = 0
+
+            [repository]
+            path = "a"
+            secret_env = "PATH"
+            "#,
+        );
+        let err = error(&dir, &[]);
+        for problem in [
+            "server.port must not be 0",
+            "replay.capacity must be at least 1",
+            "rate_limit.client_per_minute must be at least 1",
+        ] {
+            assert!(err.contains(problem), "{}", err);
+        }
+    }
+
+    fn filter(patterns: &[&str]) -> PathFilter {
+        serde_json::from_value(serde_json::json!(patterns)).unwrap()
+    }
+
+    fn matches(filter: &PathFilter, file: &str) -> bool {
+        filter.matches_any(&[file.to_string()])
+    }
+
+    #[test]
+    fn double_star_crosses_directories() {
+        let filter = filter(&["src/**"]);
+        assert!(matches(&filter, "src/main.rs"));
+        assert!(matches(&filter, "src/a/b.rs"));
+        assert!(!matches(&filter, "tests/src/main.rs"));
+        assert!(!matches(&filter, "src"));
+    }
+
+    #[test]
+    fn single_star_stays_in_its_directory() {
+        let sources = filter(&["src/*"]);
+        assert!(matches(&sources, "src/main.rs"));
+        assert!(!matches(&sources, "src/a/b.rs"));
+
+        let root = filter(&["*.toml"]);
+        assert!(matches(&root, "Cargo.toml"));
+        assert!(!matches(&root, "config/app.toml"));
+        assert!(matches(&filter(&["**/*.toml"]), "config/app.toml"));
+    }
+
+    #[test]
+    fn matches_root_files_by_name() {
+        let filter = filter(&["package.json", "package-lock.json"]);
+        assert!(filter.matches_any(&["README.md".to_string(), "package-lock.json".to_string()]));
+        assert!(!matches(&filter, "web/package.json"));
+        assert!(!filter.matches_any(&[]));
+    }
+
+    #[test]
+    fn refuses_invalid_globs() {
+        let result = serde_json::from_value::<PathFilter>(serde_json::json!(["src/[a"]));
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,162 @@
+use std::sync::Arc;
+
+use crate::config::{DeployMode, Target};
+use crate::error::Error;
+use crate::git::{self, Git};
+use crate::health;
+use crate::jobs::{JobHandle, JobState, Phase, TriggerSource};
+use crate::pipeline;
+
+/// How a deploy that made it through the pipeline ended.
+enum Deployed {
+    Healthy(String),
+    /// The new commit failed its health check and `restored` was put back in its place.
+    RolledBack { restored: String, reason: String },
+}
+
+/// Runs a started deploy job to completion, leaving its final state on `job`.
+pub async fn run(target: Arc<Target>, job: Arc<JobHandle>) {
+    match deploy(&target, &job).await {
+        Ok(Deployed::Healthy(deployed)) => {
+            tracing::info!(job_id = %job.id(), target = %target.name, commit = %deployed, "Job succeeded");
+            job.finish(JobState::Succeeded, None);
+        }
+        Ok(Deployed::RolledBack { restored, reason }) => {
+            let message = format!("{}; rolled back to {}", reason, restored);
+            tracing::warn!(job_id = %job.id(), target = %target.name, "Job rolled back: {}", message);
+            job.finish(JobState::RolledBack, Some(message));
+        }
+        Err(err) if job.cancellation().is_cancelled() => {
+            tracing::info!(job_id = %job.id(), target = %target.name, "Job cancelled");
+            job.fail(JobState::Cancelled, &err);
+        }
+        Err(err) => {
+            tracing::error!(job_id = %job.id(), target = %target.name, "Job failed: {}", err);
+            job.fail(JobState::Failed, &err);
+        }
+    }
+}
+
+async fn deploy(target: &Target, job: &JobHandle) -> Result<Deployed, Error> {
+    let git = Git {
+        job,
+        timeout: target.git_timeout,
+        repo: &target.repo,
+        git_dir: &target.git_dir,
+    };
+    if let Some(lock) = git.lock_held() {
+        return Err(Error::LockContention { lock });
+    }
+
+    let previous = git.head().await?;
+    let deployed = match job.trigger().source {
+        // The commit was live before, so it need not be on the branch any more.
+        TriggerSource::Rollback => {
+            checkout(target, &git, &job.commit()).await?;
+            head(&git).await?
+        }
+        // Release tags need not be on the deployed branch either.
+        TriggerSource::Release => {
+            let tag = job.commit();
+            git.fetch_tag(&target.remote, &tag).await?;
+            checkout(target, &git, &git::tag_ref(&tag)).await?;
+            head(&git).await?
+        }
+        TriggerSource::Push => update(target, &git, &job.commit()).await?,
+        // CI passed for this commit only, so even pull mode must not deploy a newer upstream HEAD.
+        TriggerSource::WorkflowRun => update_to(target, &git, &job.commit()).await?,
+    };
+    let commit_count = match &previous {
+        Some(previous) => git.commit_count(previous, &deployed).await?,
+        None => None,
+    };
+    job.record_update(previous.clone(), deployed.clone(), commit_count);
+    if let Some(previous) = &previous {
+        let commits = git.log(previous, &deployed).await?.unwrap_or_default();
+        let diff = git.diff_stat(previous, &deployed).await?.unwrap_or_default();
+        job.record_changes(commits, diff);
+    }
+
+    let changed = changed_files(&git, previous.as_deref(), &deployed).await?;
+    pipeline::run(&target.steps, &target.repo, changed.as_deref(), Phase::Deploy, job).await?;
+
+    let Some(check) = &target.health else {
+        return Ok(Deployed::Healthy(deployed));
+    };
+    let health = health::probe(check, job.cancellation()).await;
+    job.record_health(health.clone());
+    if health.healthy {
+        return Ok(Deployed::Healthy(deployed));
+    }
+    if job.cancellation().is_cancelled() {
+        return Err(Error::Cancelled);
+    }
+
+    let unhealthy = Error::Unhealthy(health.message.unwrap_or_default());
+    let Some(previous) = previous.filter(|previous| *previous != deployed) else {
+        return Err(unhealthy);
+    };
+    let rollback = async {
+        checkout(target, &git, &previous).await?;
+        let changed = changed_files(&git, Some(&deployed), &previous).await?;
+        pipeline::run(&target.steps, &target.repo, changed.as_deref(), Phase::Rollback, job).await
+    };
+    match rollback.await {
+        Ok(()) => Ok(Deployed::RolledBack {
+            restored: previous,
+            reason: unhealthy.to_string(),
+        }),
+        Err(err) => Err(Error::RollbackFailed {
+            reason: unhealthy.to_string(),
+            commit: previous,
+            source: Box::new(err),
+        }),
+    }
+}
+
+async fn changed_files(git: &Git<'_>, from: Option<&str>, to: &str) -> Result<Option<Vec<String>>, Error> {
+    match from {
+        Some(from) => git.changed_files(from, to).await,
+        None => Ok(None),
+    }
+}
+
+/// Brings the working tree to `commit` (or upstream HEAD in pull mode) and returns the new HEAD.
+async fn update(target: &Target, git: &Git<'_>, commit: &str) -> Result<String, Error> {
+    if target.mode == DeployMode::Pull {
+        git.pull().await?;
+        return head(git).await;
+    }
+    update_to(target, git, commit).await
+}
+
+/// Fetches the branch, checks that `commit` is on it and brings the working tree to it.
+async fn update_to(target: &Target, git: &Git<'_>, commit: &str) -> Result<String, Error> {
+    git.fetch(&target.remote, &target.branch).await?;
+
+    let tracking_ref = git::remote_ref(&target.remote, &target.branch);
+    if !git.is_ancestor(commit, &tracking_ref).await? {
+        return Err(Error::Unreachable {
+            commit: commit.to_string(),
+            rev: tracking_ref,
+        });
+    }
+
+    checkout(target, git, commit).await?;
+    head(git).await
+}
+
+/// Moves the working tree to `commit`: a detached checkout in checkout mode, a hard reset otherwise.
+async fn checkout(target: &Target, git: &Git<'_>, commit: &str) -> Result<(), Error> {
+    match target.mode {
+        DeployMode::Checkout => git.checkout_detached(commit).await?,
+        _ => git.reset_hard(commit).await?,
+    };
+    Ok(())
+}
+
+async fn head(git: &Git<'_>) -> Result<String, Error> {
+    git.head().await?.ok_or_else(|| Error::RepoNotFound {
+        path: git.git_dir.to_path_buf(),
+    })
+}
@@ -0,0 +1,229 @@
+use std::io;
+use std::path::PathBuf;
+use std::process::Output;
+use std::time::Duration;
+
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Failed to start {command}: {source}")]
+    Spawn { command: String, source: io::Error },
+
+    #[error("{command} exited with {}: {stderr}", describe_exit(*.exit_code))]
+    Exit {
+        command: String,
+        /// `None` when the process was killed by a signal.
+        exit_code: Option<i32>,
+        stderr: String,
+    },
+
+    #[error("{command} timed out after {}s", .timeout.as_secs())]
+    Timeout { command: String, timeout: Duration },
+
+    #[error("Cancelled")]
+    Cancelled,
+
+    #[error("{} is not a git repository", .path.display())]
+    RepoNotFound { path: PathBuf },
+
+    #[error("Another git process is running in this repository ({} exists)", .lock.display())]
+    LockContention { lock: PathBuf },
+
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+
+    #[error("{commit} is not reachable from {rev}")]
+    Unreachable { commit: String, rev: String },
+
+    #[error("Step {step} failed: {source}")]
+    Step { step: String, source: Box<Error> },
+
+    #[error("Health check failed: {0}")]
+    Unhealthy(String),
+
+    #[error("{reason}; rollback to {commit} failed: {source}")]
+    RollbackFailed {
+        reason: String,
+        commit: String,
+        source: Box<Error>,
+    },
+
+    #[error("Invalid or missing signature")]
+    InvalidSignature,
+
+    #[error("Invalid payload: {0}")]
+    InvalidPayload(String),
+
+    #[error("Job not found")]
+    JobNotFound,
+
+    #[error("No deploy target named {0}")]
+    TargetNotFound(String),
+
+    #[error("Deploy not found: {0}")]
+    DeployNotFound(String),
+
+    #[error("Invalid query: {0}")]
+    InvalidQuery(String),
+
+    #[error("Deploy history unavailable: {0}")]
+    History(io::Error),
+
+    #[error("Job has already finished")]
+    JobFinished,
+
+    #[error("Delivery {0} was already received")]
+    DuplicateDelivery(String),
+
+    #[error("Payload timestamp is out of range: {0}")]
+    StalePayload(String),
+
+    #[error("Too many requests {scope}; retry in {}s", retry_secs(*.retry_after))]
+    RateLimited {
+        /// `from client IP` or `for target NAME`.
+        scope: String,
+        retry_after: Duration,
+    },
+}
+
+/// JSON form of an [`Error`], used both as the HTTP error body and in job reports.
+#[derive(Clone, Debug, Serialize)]
+pub struct ErrorBody {
+    pub error: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub step: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+}
+
+fn describe_exit(exit_code: Option<i32>) -> String {
+    match exit_code {
+        Some(code) => format!("exit code {}", code),
+        None => "a signal".to_string(),
+    }
+}
+
+/// Whole seconds to wait, rounded up so a client retrying on time is let through.
+fn retry_secs(retry_after: Duration) -> u64 {
+    retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)
+}
+
+impl Error {
+    /// Maps an error from [`crate::process::output`] onto the matching variant.
+    pub fn from_io(command: &str, err: io::Error, timeout: Duration) -> Self {
+        match err.kind() {
+            io::ErrorKind::TimedOut => Error::Timeout {
+                command: command.to_string(),
+                timeout,
+            },
+            io::ErrorKind::Interrupted => Error::Cancelled,
+            _ => Error::Spawn {
+                command: command.to_string(),
+                source: err,
+            },
+        }
+    }
+
+    /// Passes a successful `output` through and turns a non-zero exit into [`Error::Exit`].
+    pub fn check(command: &str, output: Output) -> Result<Output, Self> {
+        if output.status.success() {
+            return Ok(output);
+        }
+        Err(Error::Exit {
+            command: command.to_string(),
+            exit_code: output.status.code(),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        })
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Spawn { .. } => "spawn_failed",
+            Error::Exit { .. } => "command_failed",
+            Error::Timeout { .. } => "timeout",
+            Error::Cancelled => "cancelled",
+            Error::RepoNotFound { .. } => "repo_not_found",
+            Error::LockContention { .. } => "lock_contention",
+            Error::Config(_) => "config",
+            Error::Unreachable { .. } => "unreachable_commit",
+            Error::Step { .. } => "step_failed",
+            Error::Unhealthy(_) => "unhealthy",
+            Error::RollbackFailed { .. } => "rollback_failed",
+            Error::InvalidSignature => "invalid_signature",
+            Error::InvalidPayload(_) => "invalid_payload",
+            Error::JobNotFound => "job_not_found",
+            Error::TargetNotFound(_) => "target_not_found",
+            Error::DeployNotFound(_) => "deploy_not_found",
+            Error::InvalidQuery(_) => "invalid_query",
+            Error::History(_) => "history_unavailable",
+            Error::JobFinished => "job_finished",
+            Error::DuplicateDelivery(_) => "duplicate_delivery",
+            Error::StalePayload(_) => "stale_payload",
+            Error::RateLimited { .. } => "rate_limited",
+        }
+    }
+
+    pub fn body(&self) -> ErrorBody {
+        let mut body = ErrorBody {
+            error: self.kind(),
+            message: self.to_string(),
+            step: None,
+            command: None,
+            exit_code: None,
+        };
+        let mut source = self;
+        while let Error::Step { source: inner, .. } | Error::RollbackFailed { source: inner, .. } = source {
+            if let Error::Step { step, .. } = source {
+                body.step = Some(step.clone());
+            }
+            source = inner;
+        }
+        match source {
+            Error::Exit { command, exit_code, .. } => {
+                body.command = Some(command.clone());
+                body.exit_code = *exit_code;
+            }
+            Error::Spawn { command, .. } | Error::Timeout { command, .. } => body.command = Some(command.clone()),
+            _ => {}
+        }
+        body
+    }
+}
+
+impl ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Error::Timeout { .. } => StatusCode::GATEWAY_TIMEOUT,
+            Error::Cancelled | Error::LockContention { .. } | Error::JobFinished | Error::DuplicateDelivery(_) => {
+                StatusCode::CONFLICT
+            }
+            Error::Unreachable { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::Unhealthy(_) => StatusCode::BAD_GATEWAY,
+            Error::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Error::InvalidSignature => StatusCode::UNAUTHORIZED,
+            Error::InvalidPayload(_) | Error::InvalidQuery(_) | Error::StalePayload(_) => StatusCode::BAD_REQUEST,
+            Error::JobNotFound | Error::TargetNotFound(_) | Error::DeployNotFound(_) => StatusCode::NOT_FOUND,
+            Error::Step { source, .. } => source.status_code(),
+            Error::Spawn { .. }
+            | Error::Exit { .. }
+            | Error::RepoNotFound { .. }
+            | Error::Config(_)
+            | Error::RollbackFailed { .. }
+            | Error::History(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if let Error::RateLimited { retry_after, .. } = self {
+            response.insert_header((header::RETRY_AFTER, retry_secs(*retry_after)));
+        }
+        response.json(self.body())
+    }
+}
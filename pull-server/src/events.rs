@@ -0,0 +1,211 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::http::header::HeaderMap;
+use actix_web::web::Bytes;
+use chrono::{DateTime, Utc};
+use futures_util::Stream;
+use serde::Serialize;
+
+use crate::jobs::{JobHandle, JobState, Phase, StepStatus};
+
+/// Idle streams get a comment this often so proxies do not time them out during quiet steps.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// Something that happened during a job, in the order it happened.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JobEvent {
+    /// The job was queued, started or finished.
+    Status { status: JobState },
+    StepStarted { step: String, phase: Phase },
+    /// One line of a command's output, without its line terminator.
+    Output {
+        /// The pipeline step running the command; `None` for git.
+        step: Option<String>,
+        stream: OutputStream,
+        line: String,
+    },
+    CommandFinished { command: String, exit_code: Option<i32> },
+    StepFinished {
+        step: String,
+        phase: Phase,
+        status: StepStatus,
+        exit_code: Option<i32>,
+        duration_ms: u64,
+    },
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Event {
+    /// Position in the job's event log, sent as the SSE `id` so clients can resume.
+    pub id: usize,
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: JobEvent,
+}
+
+impl JobEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            JobEvent::Status { .. } => "status",
+            JobEvent::StepStarted { .. } => "step_started",
+            JobEvent::Output { .. } => "output",
+            JobEvent::CommandFinished { .. } => "command_finished",
+            JobEvent::StepFinished { .. } => "step_finished",
+        }
+    }
+}
+
+impl Event {
+    fn to_sse(&self) -> String {
+        let data = serde_json::to_string(self).expect("events serialize");
+        format!("id: {}\nevent: {}\ndata: {}\n\n", self.id, self.event.name(), data)
+    }
+}
+
+/// The first event to send a client, which is past the `Last-Event-ID` it saw before reconnecting.
+pub fn resume_from(headers: &HeaderMap) -> usize {
+    headers
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<usize>().ok())
+        .map_or(0, |id| id + 1)
+}
+
+/// Server-Sent Events for `job` starting at event `from`: the backlog first, then live events
+/// until the job finishes.
+pub fn stream(job: Arc<JobHandle>, from: usize) -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+    let updates = job.subscribe();
+    futures_util::stream::unfold(Some((job, updates, from)), |state| async move {
+        let (job, mut updates, mut next) = state?;
+        loop {
+            // Marked seen before reading, so an event pushed in between still wakes us below.
+            updates.borrow_and_update();
+            let (events, finished) = job.events_since(next);
+            if !events.is_empty() {
+                next += events.len();
+                let chunk: String = events.iter().map(Event::to_sse).collect();
+                // Nothing follows the final status event, so a finished job's batch is the last.
+                let state = (!finished).then_some((job, updates, next));
+                return Some((Ok(Bytes::from(chunk)), state));
+            }
+            if finished {
+                return None;
+            }
+            match tokio::time::timeout(KEEP_ALIVE, updates.changed()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(_)) => return None,
+                Err(_) => return Some((Ok(Bytes::from_static(b": keep-alive\n\n")), Some((job, updates, next)))),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::{HeaderName, HeaderValue};
+    use futures_util::StreamExt;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::jobs::{Jobs, Trigger, TriggerSource};
+    use crate::testing;
+
+    fn job() -> Arc<JobHandle> {
+        let trigger = Trigger {
+            source: TriggerSource::Push,
+            requester: None,
+            remote_addr: None,
+        };
+        Jobs::default().create(&testing::target(std::path::Path::new("/srv/web")), "a".repeat(40), trigger)
+    }
+
+    /// The `(id, event, data)` of each event in an SSE chunk.
+    fn parse(chunk: &[u8]) -> Vec<(usize, String, Value)> {
+        std::str::from_utf8(chunk)
+            .unwrap()
+            .split_terminator("\n\n")
+            .map(|event| {
+                let field = |name: &str| {
+                    event
+                        .lines()
+                        .find_map(|line| line.strip_prefix(name))
+                        .unwrap_or_else(|| panic!("No {} in {:?}", name, event))
+                        .to_string()
+                };
+                (
+                    field("id: ").parse().unwrap(),
+                    field("event: "),
+                    serde_json::from_str(&field("data: ")).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    async fn all(job: &Arc<JobHandle>, from: usize) -> Vec<(usize, String, Value)> {
+        let chunks: Vec<_> = stream(job.clone(), from).collect().await;
+        chunks.iter().flat_map(|chunk| parse(chunk.as_ref().unwrap())).collect()
+    }
+
+    fn finished_job() -> Arc<JobHandle> {
+        let job = job();
+        assert!(job.start());
+        job.record_step_start("install", Phase::Deploy);
+        job.finish(JobState::Succeeded, None);
+        job
+    }
+
+    #[actix_web::test]
+    async fn replays_the_backlog_of_a_finished_job() {
+        let events = all(&finished_job(), 0).await;
+        let summary: Vec<(usize, &str)> = events.iter().map(|(id, name, _)| (*id, name.as_str())).collect();
+        assert_eq!(summary, [(0, "status"), (1, "status"), (2, "step_started"), (3, "status")]);
+        assert_eq!(events[0].2["status"], "queued");
+        assert_eq!(events[2].2["step"], "install");
+        assert_eq!(events[3].2["id"], 3);
+        assert_eq!(events[3].2["status"], "succeeded");
+    }
+
+    #[actix_web::test]
+    async fn resumes_after_the_last_event_id() {
+        let job = finished_job();
+        let ids: Vec<usize> = all(&job, 2).await.iter().map(|(id, _, _)| *id).collect();
+        assert_eq!(ids, [2, 3]);
+        assert!(all(&job, 4).await.is_empty());
+
+        let headers = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(HeaderName::from_static("last-event-id"), HeaderValue::from_str(value).unwrap());
+            headers
+        };
+        assert_eq!(resume_from(&HeaderMap::new()), 0);
+        assert_eq!(resume_from(&headers("1")), 2);
+        assert_eq!(resume_from(&headers("garbage")), 0);
+    }
+
+    #[actix_web::test]
+    async fn follows_a_running_job_until_it_finishes() {
+        let job = job();
+        assert!(job.start());
+        let mut events = Box::pin(stream(job.clone(), 0));
+        let backlog = parse(&events.next().await.unwrap().unwrap());
+        assert_eq!(backlog.len(), 2);
+
+        let finisher = job.clone();
+        actix_web::rt::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            finisher.finish(JobState::Failed, None);
+        });
+        let live = parse(&events.next().await.unwrap().unwrap());
+        assert_eq!((live[0].0, live[0].2["status"].as_str()), (2, Some("failed")));
+        assert!(events.next().await.is_none());
+    }
+}
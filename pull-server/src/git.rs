@@ -0,0 +1,250 @@
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio_util::sync::CancellationToken;
+
+use crate::error::Error;
+use crate::jobs::{CommitSummary, DiffStat, JobHandle};
+use crate::process;
+
+/// Deploy reports list at most this many commits, newest first.
+pub const MAX_LOG_COMMITS: usize = 50;
+
+/// Runs git commands in `repo` on behalf of `job`, each bounded by `timeout`.
+pub struct Git<'a> {
+    pub job: &'a JobHandle,
+    pub timeout: Duration,
+    pub repo: &'a Path,
+    /// The repository's `.git` directory, used to spot a held index lock.
+    pub git_dir: &'a Path,
+}
+
+impl Git<'_> {
+    /// Runs a read-only command, killed if the job is cancelled.
+    async fn run(&self, args: &[&str]) -> Result<Output, Error> {
+        self.spawn(args, self.job.cancellation()).await
+    }
+
+    async fn spawn(&self, args: &[&str], cancel: &CancellationToken) -> Result<Output, Error> {
+        let mut command = Command::new("git");
+        command.args(args).current_dir(self.repo).env("GIT_TERMINAL_PROMPT", "0");
+        process::run(self.job, command, self.timeout, cancel).await
+    }
+
+    /// Runs a command that modifies the repository, failing on a non-zero exit.
+    ///
+    /// Killing one midway would leave `index.lock` behind and the tree half updated, failing every
+    /// later deploy, so a cancelled job stops before the command rather than during it.
+    async fn run_checked(&self, label: &str, args: &[&str]) -> Result<Output, Error> {
+        if self.job.cancellation().is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        let output = self.spawn(args, &CancellationToken::new()).await?;
+        Error::check(label, output).map_err(|err| match self.lock_held() {
+            Some(lock) => Error::LockContention { lock },
+            None => err,
+        })
+    }
+
+    /// The index lock path, if some git process currently holds it.
+    pub fn lock_held(&self) -> Option<PathBuf> {
+        let lock = self.git_dir.join("index.lock");
+        lock.exists().then_some(lock)
+    }
+
+    pub async fn pull(&self) -> Result<Output, Error> {
+        self.run_checked("git pull", &["pull"]).await
+    }
+
+    /// Fetches `branch` from `remote`, force-updating its remote-tracking ref.
+    pub async fn fetch(&self, remote: &str, branch: &str) -> Result<Output, Error> {
+        let refspec = format!("+refs/heads/{}:{}", branch, remote_ref(remote, branch));
+        self.run_checked("git fetch", &["fetch", remote, &refspec]).await
+    }
+
+    /// Fetches `tag` from `remote`, moving the local tag if it was re-tagged.
+    pub async fn fetch_tag(&self, remote: &str, tag: &str) -> Result<Output, Error> {
+        let refspec = format!("+{}:{}", tag_ref(tag), tag_ref(tag));
+        self.run_checked("git fetch", &["fetch", "--no-tags", remote, &refspec]).await
+    }
+
+    /// Whether `commit` is reachable from `rev`. Unknown commits count as unreachable.
+    pub async fn is_ancestor(&self, commit: &str, rev: &str) -> Result<bool, Error> {
+        let output = self.run(&["merge-base", "--is-ancestor", commit, rev]).await?;
+        Ok(output.status.success())
+    }
+
+    pub async fn reset_hard(&self, commit: &str) -> Result<Output, Error> {
+        self.run_checked("git reset", &["reset", "--hard", commit]).await
+    }
+
+    pub async fn checkout_detached(&self, commit: &str) -> Result<Output, Error> {
+        self.run_checked("git checkout", &["checkout", "--force", "--detach", commit])
+            .await
+    }
+
+    /// Paths touched between two commits, including both sides of renames.
+    pub async fn changed_files(&self, from: &str, to: &str) -> Result<Option<Vec<String>>, Error> {
+        let output = self.run(&["diff", "--name-only", "--no-renames", from, to]).await?;
+        Ok(output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).lines().map(str::to_string).collect()))
+    }
+
+    /// Number of commits reachable from `to` but not from `from`.
+    pub async fn commit_count(&self, from: &str, to: &str) -> Result<Option<u64>, Error> {
+        let range = format!("{}..{}", from, to);
+        let output = self.run(&["rev-list", "--count", &range]).await?;
+        Ok(output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).trim().parse().ok())
+            .flatten())
+    }
+
+    /// The newest commits reachable from `to` but not from `from`, up to [`MAX_LOG_COMMITS`].
+    pub async fn log(&self, from: &str, to: &str) -> Result<Option<Vec<CommitSummary>>, Error> {
+        let range = format!("{}..{}", from, to);
+        let max_count = format!("--max-count={}", MAX_LOG_COMMITS);
+        let output = self
+            .run(&["log", "--no-color", "--format=%H%x1f%an%x1f%s", &max_count, &range])
+            .await?;
+        if !output.status.success() {
+            return Ok(None);
+        }
+        let commits = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.splitn(3, '\x1f');
+                Some(CommitSummary {
+                    sha: fields.next()?.to_string(),
+                    author: fields.next()?.to_string(),
+                    subject: fields.next()?.to_string(),
+                })
+            })
+            .collect();
+        Ok(Some(commits))
+    }
+
+    /// Files changed and lines added or removed between two commits.
+    pub async fn diff_stat(&self, from: &str, to: &str) -> Result<Option<DiffStat>, Error> {
+        let output = self.run(&["diff", "--numstat", "--no-renames", from, to]).await?;
+        if !output.status.success() {
+            return Ok(None);
+        }
+        let mut stat = DiffStat::default();
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            let mut fields = line.split('\t');
+            // Binary files report "-" for both counts.
+            stat.insertions += fields.next().and_then(|count| count.parse::<u64>().ok()).unwrap_or(0);
+            stat.deletions += fields.next().and_then(|count| count.parse::<u64>().ok()).unwrap_or(0);
+            stat.files_changed += 1;
+        }
+        Ok(Some(stat))
+    }
+
+    pub async fn head(&self) -> Result<Option<String>, Error> {
+        let output = self.run(&["rev-parse", "HEAD"]).await?;
+        Ok(output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string()))
+    }
+}
+
+/// HEAD of `repo`, read outside of any job.
+pub async fn current_head(repo: &Path, timeout: Duration) -> Result<Option<String>, Error> {
+    let mut command = Command::new("git");
+    command.args(["rev-parse", "HEAD"]).current_dir(repo);
+    let output = process::output(command, timeout, &CancellationToken::new(), &mut |_, _| {})
+        .await
+        .map_err(|err| Error::from_io("git rev-parse", err, timeout))?;
+    Ok(output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string()))
+}
+
+/// Resolves the `.git` directory of the repository whose working tree is `repo`.
+pub fn find_git_dir(repo: &Path) -> Result<PathBuf, Error> {
+    let output = std::process::Command::new("git")
+        .args(["rev-parse", "--absolute-git-dir"])
+        .current_dir(repo)
+        .output()
+        .map_err(|source| Error::Spawn {
+            command: "git rev-parse".to_string(),
+            source,
+        })?;
+    if !output.status.success() {
+        return Err(Error::RepoNotFound {
+            path: repo.to_path_buf(),
+        });
+    }
+    Ok(PathBuf::from(String::from_utf8_lossy(&output.stdout).trim()))
+}
+
+/// Whether `value` looks like a full 40-character commit SHA.
+pub fn is_sha(value: &str) -> bool {
+    value.len() == 40 && value.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+/// The 7-character abbreviation of `sha` used in messages, or all of it if shorter.
+pub fn short(sha: &str) -> &str {
+    sha.get(..7).unwrap_or(sha)
+}
+
+/// Whether `value` can be used as a tag name, following the rules of `git check-ref-format`
+/// closely enough that it never reads as an option or a revision expression.
+pub fn is_tag_name(value: &str) -> bool {
+    !value.is_empty()
+        && !value.starts_with(['-', '.', '/'])
+        && !value.ends_with(['.', '/'])
+        && !value.ends_with(".lock")
+        && !value.contains("..")
+        && !value.contains("@{")
+        && !value.contains("//")
+        && !value.contains("/.")
+        && !value
+            .chars()
+            .any(|c| c.is_ascii_control() || c.is_whitespace() || "~^:?*[\\".contains(c))
+}
+
+pub fn remote_ref(remote: &str, branch: &str) -> String {
+    format!("refs/remotes/{}/{}", remote, branch)
+}
+
+pub fn tag_ref(tag: &str) -> String {
+    format!("refs/tags/{}", tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_tag_names() {
+        for name in ["v1.2.0", "release/2024-05", "v1.0.0-rc.1+build.5", "latest"] {
+            assert!(is_tag_name(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn rejects_options_revisions_and_bad_refs() {
+        let names = [
+            "", "-v1", "--upload-pack=x", ".hidden", "/v1", "v1/", "v1.", "v1.lock", "v1..v2", "v1@{0}", "a//b",
+            "a/.b", "v1 2", "v1\t", "v1~1", "v1^", "v1:x", "v1?", "v1*", "v1[", "a\\b",
+        ];
+        for name in names {
+            assert!(!is_tag_name(name), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn recognizes_full_shas() {
+        assert!(is_sha("0123456789abcdef0123456789ABCDEF01234567"));
+        assert!(!is_sha("0123456"));
+        assert!(!is_sha("g123456789abcdef0123456789abcdef01234567"));
+    }
+}
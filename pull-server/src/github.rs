@@ -0,0 +1,345 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::rt::task::JoinHandle;
+use reqwest::header;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::config::{GitHubConfig, GitHubTarget};
+use crate::git;
+use crate::jobs::{Job, JobState};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// GitHub rejects longer status descriptions.
+const MAX_DESCRIPTION_CHARS: usize = 140;
+
+/// Records deploys as GitHub Deployments with deployment and commit statuses.
+pub struct GitHub {
+    client: reqwest::Client,
+    api_url: String,
+    token: String,
+    public_url: Option<String>,
+}
+
+/// A job being reported, from its start to [`Report::finish`].
+pub struct Report {
+    github: Arc<GitHub>,
+    environment: String,
+    repository: String,
+    /// Commit or, for releases, tag being deployed.
+    git_ref: String,
+    job_id: Uuid,
+    /// Creates the deployment; yields its ID, or `None` if GitHub refused.
+    deployment: JoinHandle<Option<u64>>,
+}
+
+#[derive(Deserialize)]
+struct Deployment {
+    id: u64,
+}
+
+impl GitHub {
+    pub fn new(config: &GitHubConfig) -> reqwest::Result<Self> {
+        let mut headers = header::HeaderMap::new();
+        headers.insert(header::ACCEPT, header::HeaderValue::from_static("application/vnd.github+json"));
+        headers.insert("X-GitHub-Api-Version", header::HeaderValue::from_static("2022-11-28"));
+        let client = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .user_agent(concat!("pull-server/", env!("CARGO_PKG_VERSION")))
+            .default_headers(headers)
+            .build()?;
+        Ok(GitHub {
+            client,
+            api_url: config.api_url.trim_end_matches('/').to_string(),
+            token: config.token.clone(),
+            public_url: config.public_url.as_ref().map(|url| url.trim_end_matches('/').to_string()),
+        })
+    }
+
+    /// Creates a deployment of the started `job` and marks it and its commit in progress, in the
+    /// background so GitHub being slow or down never holds up the deploy.
+    pub fn start(self: &Arc<Self>, target: &GitHubTarget, job: &Job) -> Report {
+        let github = self.clone();
+        let repository = target.repository.clone();
+        let environment = target.environment.clone();
+        let git_ref = job.commit.clone();
+        let job_id = job.id;
+        let deployment = actix_web::rt::spawn(async move {
+            let description = format!("Deploying {} to {}", git::short(&git_ref), environment);
+            // A tag's commit is only known once it has been fetched.
+            if git::is_sha(&git_ref) {
+                github
+                    .commit_status(&repository, &git_ref, &environment, "pending", &description, job_id)
+                    .await;
+            }
+            let body = json!({
+                "ref": git_ref,
+                "environment": environment,
+                "description": truncate(&description),
+                "auto_merge": false,
+                // Deploying is not gated on checks; the dev branch deploys while CI still runs.
+                "required_contexts": [],
+                "payload": { "job_id": job_id },
+            });
+            let deployment: Deployment = github.post(&format!("/repos/{}/deployments", repository), &body).await?;
+            github
+                .deployment_status(&repository, deployment.id, "in_progress", &description, job_id)
+                .await;
+            Some(deployment.id)
+        });
+        Report {
+            github: self.clone(),
+            environment: target.environment.clone(),
+            repository: target.repository.clone(),
+            git_ref: job.commit.clone(),
+            job_id: job.id,
+            deployment,
+        }
+    }
+
+    async fn deployment_status(&self, repository: &str, id: u64, state: &str, description: &str, job_id: Uuid) {
+        let mut body = json!({
+            "state": state,
+            "description": truncate(description),
+        });
+        if let Some(url) = self.job_url(job_id) {
+            body["log_url"] = Value::from(url);
+        }
+        self.post::<Value>(&format!("/repos/{}/deployments/{}/statuses", repository, id), &body)
+            .await;
+    }
+
+    async fn commit_status(
+        &self,
+        repository: &str,
+        sha: &str,
+        environment: &str,
+        state: &str,
+        description: &str,
+        job_id: Uuid,
+    ) {
+        let mut body = json!({
+            "state": state,
+            "description": truncate(description),
+            "context": format!("pull-server/{}", environment),
+        });
+        if let Some(url) = self.job_url(job_id) {
+            body["target_url"] = Value::from(url);
+        }
+        self.post::<Value>(&format!("/repos/{}/statuses/{}", repository, sha), &body).await;
+    }
+
+    /// `GET /jobs/{id}` on this server, if the configuration says where it is reachable.
+    fn job_url(&self, job_id: Uuid) -> Option<String> {
+        self.public_url.as_ref().map(|url| format!("{}/jobs/{}", url, job_id))
+    }
+
+    /// Sends one API request, logging rather than returning failures.
+    async fn post<T: for<'de> Deserialize<'de>>(&self, path: &str, body: &Value) -> Option<T> {
+        let result = self
+            .client
+            .post(format!("{}{}", self.api_url, path))
+            .bearer_auth(&self.token)
+            .json(body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        let response = match result {
+            Ok(response) => response,
+            Err(err) => {
+                tracing::warn!(path = path, "GitHub request failed: {}", err);
+                return None;
+            }
+        };
+        match response.json().await {
+            Ok(value) => Some(value),
+            Err(err) => {
+                tracing::warn!(path = path, "Unexpected GitHub response: {}", err);
+                None
+            }
+        }
+    }
+}
+
+impl Report {
+    /// Posts the final deployment and commit statuses once the deployment has been created.
+    pub fn finish(self, job: &Job) {
+        let (deployment_state, commit_state, description) = match job.state {
+            JobState::Succeeded => ("success", "success", format!("Deployed to {}", self.environment)),
+            JobState::Failed => (
+                "failure",
+                "failure",
+                job.error
+                    .as_ref()
+                    .map_or_else(|| "Deploy failed".to_string(), |error| error.message.clone()),
+            ),
+            JobState::RolledBack => (
+                "failure",
+                "failure",
+                job.message.clone().unwrap_or_else(|| "Rolled back".to_string()),
+            ),
+            JobState::Cancelled => ("error", "error", "Deploy cancelled".to_string()),
+            JobState::Queued | JobState::Running => return,
+        };
+        let sha = job.new_sha.clone().or_else(|| git::is_sha(&self.git_ref).then(|| self.git_ref.clone()));
+        actix_web::rt::spawn(async move {
+            let deployment = self.deployment.await.ok().flatten();
+            if let Some(id) = deployment {
+                self.github
+                    .deployment_status(&self.repository, id, deployment_state, &description, self.job_id)
+                    .await;
+            }
+            if let Some(sha) = sha {
+                self.github
+                    .commit_status(&self.repository, &sha, &self.environment, commit_state, &description, self.job_id)
+                    .await;
+            }
+        });
+    }
+}
+
+fn truncate(description: &str) -> String {
+    description.chars().take(MAX_DESCRIPTION_CHARS).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorBody;
+    use crate::testing::{self, MockServer};
+
+    const COMMIT: &str = "1111111111111111111111111111111111111111";
+
+    /// A mock API creating deployment 42, or refusing to if `refuse_deployments`.
+    fn api(refuse_deployments: bool) -> (MockServer, Arc<GitHub>) {
+        let server = MockServer::start(move |request| match request.path.as_str() {
+            "/repos/octo/web/deployments" if refuse_deployments => (422, r#"{"message":"Conflict"}"#.to_string()),
+            "/repos/octo/web/deployments" => (201, r#"{"id":42}"#.to_string()),
+            _ => (201, "{}".to_string()),
+        });
+        let github = GitHub::new(&GitHubConfig {
+            api_url: format!("{}/", server.url),
+            token: "t0ken".to_string(),
+            public_url: Some("https://deploy.example.com".to_string()),
+        })
+        .unwrap();
+        (server, Arc::new(github))
+    }
+
+    fn target() -> GitHubTarget {
+        GitHubTarget {
+            repository: "octo/web".to_string(),
+            environment: "production".to_string(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn reports_a_successful_deploy() {
+        let (server, github) = api(false);
+        let mut job = testing::job(JobState::Running);
+        let report = github.start(&target(), &job);
+        let log_url = format!("https://deploy.example.com/jobs/{}", job.id);
+
+        let request = server.next().await;
+        assert_eq!(request.path, format!("/repos/octo/web/statuses/{}", COMMIT));
+        assert_eq!(request.headers["authorization"], "Bearer t0ken");
+        assert_eq!(request.body["state"], "pending");
+        assert_eq!(request.body["context"], "pull-server/production");
+        assert_eq!(request.body["target_url"], log_url.as_str());
+        let request = server.next().await;
+        assert_eq!(request.path, "/repos/octo/web/deployments");
+        assert_eq!(request.body["ref"], COMMIT);
+        assert_eq!(request.body["environment"], "production");
+        assert_eq!(request.body["payload"]["job_id"], job.id.to_string());
+        let request = server.next().await;
+        assert_eq!(request.path, "/repos/octo/web/deployments/42/statuses");
+        assert_eq!(request.body["state"], "in_progress");
+        assert_eq!(request.body["log_url"], log_url.as_str());
+
+        job.state = JobState::Succeeded;
+        job.new_sha = Some(COMMIT.to_string());
+        report.finish(&job);
+        let request = server.next().await;
+        assert_eq!(request.path, "/repos/octo/web/deployments/42/statuses");
+        assert_eq!(request.body["state"], "success");
+        let request = server.next().await;
+        assert_eq!(request.path, format!("/repos/octo/web/statuses/{}", COMMIT));
+        assert_eq!(request.body["state"], "success");
+        assert_eq!(request.body["description"], "Deployed to production");
+    }
+
+    #[actix_web::test]
+    async fn reports_a_failed_deploy() {
+        let (server, github) = api(false);
+        let mut job = testing::job(JobState::Running);
+        let report = github.start(&target(), &job);
+        job.state = JobState::Failed;
+        job.error = Some(ErrorBody {
+            error: "command_failed",
+            message: "npm ci exited with 1".to_string(),
+            step: None,
+            command: None,
+            exit_code: Some(1),
+        });
+        report.finish(&job);
+
+        let mut requests = Vec::new();
+        for _ in 0..5 {
+            requests.push(server.next().await);
+        }
+        let states: Vec<(&str, &str)> = requests
+            .iter()
+            .map(|request| (request.path.as_str(), request.body["state"].as_str().unwrap_or_default()))
+            .collect();
+        assert_eq!(
+            states,
+            [
+                ("/repos/octo/web/statuses/1111111111111111111111111111111111111111", "pending"),
+                ("/repos/octo/web/deployments", ""),
+                ("/repos/octo/web/deployments/42/statuses", "in_progress"),
+                ("/repos/octo/web/deployments/42/statuses", "failure"),
+                ("/repos/octo/web/statuses/1111111111111111111111111111111111111111", "failure"),
+            ]
+        );
+        assert_eq!(requests[4].body["description"], "npm ci exited with 1");
+    }
+
+    #[actix_web::test]
+    async fn sets_the_commit_status_when_the_deployment_is_refused() {
+        let (server, github) = api(true);
+        let mut job = testing::job(JobState::Running);
+        let report = github.start(&target(), &job);
+        assert_eq!(server.next().await.body["state"], "pending");
+        assert_eq!(server.next().await.path, "/repos/octo/web/deployments");
+
+        job.state = JobState::Cancelled;
+        report.finish(&job);
+        let request = server.next().await;
+        assert_eq!(request.path, format!("/repos/octo/web/statuses/{}", COMMIT));
+        assert_eq!(request.body["state"], "error");
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(server.received().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn waits_for_the_tag_to_resolve_before_a_commit_status() {
+        let (server, github) = api(false);
+        let mut job = testing::job(JobState::Running);
+        job.commit = "v1.2.0".to_string();
+        let report = github.start(&target(), &job);
+        let request = server.next().await;
+        assert_eq!(request.path, "/repos/octo/web/deployments");
+        assert_eq!(request.body["ref"], "v1.2.0");
+        assert_eq!(server.next().await.body["state"], "in_progress");
+
+        job.state = JobState::Succeeded;
+        job.new_sha = Some(COMMIT.to_string());
+        report.finish(&job);
+        assert_eq!(server.next().await.body["state"], "success");
+        let request = server.next().await;
+        assert_eq!(request.path, format!("/repos/octo/web/statuses/{}", COMMIT));
+        assert_eq!(request.body["state"], "success");
+    }
+}
@@ -0,0 +1,71 @@
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::HealthCheck;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct HealthResult {
+    pub healthy: bool,
+    pub attempts: u32,
+    /// HTTP status of the last response, if one arrived at all.
+    pub status: Option<u16>,
+    pub message: Option<String>,
+}
+
+/// Polls the health URL until it answers with a 2xx status or the retries run out.
+pub async fn probe(check: &HealthCheck, cancel: &CancellationToken) -> HealthResult {
+    let mut result = HealthResult {
+        healthy: false,
+        attempts: 0,
+        status: None,
+        message: None,
+    };
+
+    let client = match reqwest::Client::builder()
+        .timeout(check.timeout)
+        .danger_accept_invalid_certs(check.insecure)
+        .build()
+    {
+        Ok(client) => client,
+        Err(err) => {
+            result.message = Some(format!("Failed to build HTTP client: {}", err));
+            return result;
+        }
+    };
+
+    for attempt in 1..=check.retries.max(1) {
+        if attempt > 1 {
+            tokio::select! {
+                _ = tokio::time::sleep(check.interval) => {}
+                _ = cancel.cancelled() => break,
+            }
+        }
+        result.attempts = attempt;
+
+        let response = tokio::select! {
+            response = client.get(&check.url).send() => response,
+            _ = cancel.cancelled() => break,
+        };
+        match response {
+            Ok(response) if response.status().is_success() => {
+                result.healthy = true;
+                result.status = Some(response.status().as_u16());
+                result.message = None;
+                return result;
+            }
+            Ok(response) => {
+                result.status = Some(response.status().as_u16());
+                result.message = Some(format!("{} answered {}", check.url, response.status()));
+            }
+            Err(err) => {
+                result.status = None;
+                result.message = Some(format!("{} unreachable: {}", check.url, err));
+            }
+        }
+    }
+
+    if cancel.is_cancelled() {
+        result.message = Some("Cancelled".to_string());
+    }
+    result
+}
@@ -0,0 +1,193 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use actix_web::web;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::jobs::{Job, JobState};
+
+/// Entries returned by `GET /deploys` when no `limit` is given.
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 1000;
+
+/// Append-only JSON Lines log of finished deploys, one job report per line without its command
+/// output. Survives restarts, unlike the in-memory job registry.
+pub struct History {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+/// Query string of `GET /deploys`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Filter {
+    pub target: Option<String>,
+    pub status: Option<JobState>,
+    /// Only deploys that finished at or after this time.
+    pub since: Option<DateTime<Utc>>,
+    /// Only deploys that finished at or before this time.
+    pub until: Option<DateTime<Utc>>,
+    pub limit: Option<usize>,
+}
+
+impl History {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(History {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn append(&self, job: &Job) -> io::Result<()> {
+        let mut entry = serde_json::to_value(job)?;
+        if let Value::Object(fields) = &mut entry {
+            fields.remove("commands");
+        }
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        // One write per entry keeps lines whole even if another process appends too.
+        self.file.lock().unwrap().write_all(&line)
+    }
+
+    /// Entries matching `filter`, newest first.
+    pub async fn query(&self, filter: &Filter) -> io::Result<Vec<Value>> {
+        let limit = filter.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+        Ok(self
+            .entries()
+            .await?
+            .into_iter()
+            .filter(|entry| filter.matches(entry))
+            .take(limit)
+            .collect())
+    }
+
+    /// Every entry, newest first. The file is read and parsed on the blocking thread pool, since
+    /// it grows with every deploy.
+    pub async fn entries(&self) -> io::Result<Vec<Value>> {
+        let path = self.path.clone();
+        web::block(move || read_entries(&path)).await.map_err(io::Error::other)?
+    }
+}
+
+fn read_entries(path: &Path) -> io::Result<Vec<Value>> {
+    let contents = fs::read_to_string(path)?;
+    Ok(contents
+        .lines()
+        .rev()
+        // A line cut short by a crash is skipped rather than failing the whole query.
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .collect())
+}
+
+impl Filter {
+    fn matches(&self, entry: &Value) -> bool {
+        if let Some(target) = &self.target
+            && entry["target"].as_str() != Some(target.as_str())
+        {
+            return false;
+        }
+        if let Some(status) = self.status
+            && serde_json::from_value::<JobState>(entry["status"].clone()).ok() != Some(status)
+        {
+            return false;
+        }
+        let finished_at = entry["finished_at"]
+            .as_str()
+            .and_then(|finished_at| finished_at.parse::<DateTime<Utc>>().ok());
+        if let Some(since) = self.since
+            && finished_at.is_none_or(|finished_at| finished_at < since)
+        {
+            return false;
+        }
+        if let Some(until) = self.until
+            && finished_at.is_none_or(|finished_at| finished_at > until)
+        {
+            return false;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::{self, TempDir};
+
+    fn filter(query: &str) -> Filter {
+        web::Query::<Filter>::from_query(query).unwrap().into_inner()
+    }
+
+    fn entry(target: &str, status: &str, finished_at: &str) -> Value {
+        json!({"target": target, "status": status, "finished_at": finished_at})
+    }
+
+    #[test]
+    fn filters_by_target_and_status() {
+        let entry = entry("web", "succeeded", "2024-05-01T12:00:00Z");
+        assert!(filter("").matches(&entry));
+        assert!(filter("target=web&status=succeeded").matches(&entry));
+        assert!(!filter("target=api").matches(&entry));
+        assert!(!filter("status=failed").matches(&entry));
+        assert!(!filter("target=web&status=rolled_back").matches(&entry));
+        assert!(web::Query::<Filter>::from_query("status=done").is_err());
+        assert!(web::Query::<Filter>::from_query("branch=main").is_err());
+    }
+
+    #[test]
+    fn filters_by_finish_time_inclusively() {
+        let entry = entry("web", "failed", "2024-05-01T12:00:00Z");
+        assert!(filter("since=2024-05-01T12:00:00Z&until=2024-05-01T12:00:00Z").matches(&entry));
+        assert!(filter("since=2024-05-01T00:00:00Z").matches(&entry));
+        assert!(!filter("since=2024-05-01T12:00:01Z").matches(&entry));
+        assert!(filter("until=2024-05-02T00:00:00%2B02:00").matches(&entry));
+        assert!(!filter("until=2024-05-01T11:59:59Z").matches(&entry));
+        // An entry without a finish time only passes filters that do not ask for one.
+        let unfinished = json!({"target": "web", "status": "failed"});
+        assert!(filter("target=web").matches(&unfinished));
+        assert!(!filter("since=2024-05-01T00:00:00Z").matches(&unfinished));
+        assert!(!filter("until=2024-05-01T00:00:00Z").matches(&unfinished));
+    }
+
+    #[actix_web::test]
+    async fn returns_the_newest_matches_up_to_the_limit() {
+        let dir = TempDir::new();
+        let history = History::open(&dir.path().join("deploys.jsonl")).unwrap();
+        let mut ids = Vec::new();
+        for (target, state) in [
+            ("web", JobState::Succeeded),
+            ("api", JobState::Succeeded),
+            ("web", JobState::Failed),
+            ("web", JobState::Succeeded),
+        ] {
+            let mut job = testing::job(state);
+            job.target = target.to_string();
+            job.finished_at = Some(Utc::now());
+            history.append(&job).unwrap();
+            ids.push(job.id.to_string());
+        }
+        fs::OpenOptions::new()
+            .append(true)
+            .open(dir.path().join("deploys.jsonl"))
+            .unwrap()
+            .write_all(b"{\"target\":\"web\",\"sta")
+            .unwrap();
+
+        let job_ids = async |query: &str| -> Vec<String> {
+            let entries = history.query(&filter(query)).await.unwrap();
+            entries.iter().map(|entry| entry["job_id"].as_str().unwrap().to_string()).collect()
+        };
+        assert_eq!(history.entries().await.unwrap().len(), 4);
+        assert_eq!(job_ids("target=web").await, [ids[3].as_str(), ids[2].as_str(), ids[0].as_str()]);
+        assert_eq!(job_ids("target=web&limit=2").await, [ids[3].as_str(), ids[2].as_str()]);
+        assert_eq!(job_ids("status=succeeded&limit=1").await, [ids[3].as_str()]);
+        assert!(history.query(&filter("status=cancelled")).await.unwrap().is_empty());
+        assert!(history.entries().await.unwrap().iter().all(|entry| entry.get("commands").is_none()));
+    }
+}
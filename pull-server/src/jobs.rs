@@ -0,0 +1,489 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::config::Target;
+use crate::error::{Error, ErrorBody};
+use crate::events::{Event, JobEvent, OutputStream};
+use crate::health::HealthResult;
+
+/// Finished jobs beyond this many are forgotten, oldest first.
+const MAX_JOBS: usize = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+    /// The deploy failed its health check and the previous commit was restored.
+    RolledBack,
+}
+
+impl JobState {
+    pub fn is_finished(self) -> bool {
+        !matches!(self, JobState::Queued | JobState::Running)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Succeeded => "succeeded",
+            JobState::Failed => "failed",
+            JobState::Cancelled => "cancelled",
+            JobState::RolledBack => "rolled_back",
+        }
+    }
+}
+
+/// What asked for a deploy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TriggerSource {
+    /// A push webhook.
+    Push,
+    /// A GitHub Actions workflow that finished successfully.
+    WorkflowRun,
+    /// A published GitHub release; the job's commit is the release tag.
+    Release,
+    /// `POST /deploy/{target}/rollback`.
+    Rollback,
+}
+
+impl TriggerSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TriggerSource::Push => "push",
+            TriggerSource::WorkflowRun => "workflow_run",
+            TriggerSource::Release => "release",
+            TriggerSource::Rollback => "rollback",
+        }
+    }
+}
+
+/// Who asked for a deploy, kept for the deploy history.
+#[derive(Clone, Debug, Serialize)]
+pub struct Trigger {
+    pub source: TriggerSource,
+    /// Name reported by the trigger, e.g. the pusher.
+    pub requester: Option<String>,
+    /// Address the request came from.
+    pub remote_addr: Option<String>,
+}
+
+/// Whether the update moved HEAD at all.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Updated,
+    /// HEAD was already at the requested commit.
+    NoOp,
+}
+
+/// One command run on behalf of a job, with its captured output.
+#[derive(Clone, Debug, Serialize)]
+pub struct CommandRecord {
+    pub command: String,
+    /// `None` when the process was killed or could not be started.
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepStatus {
+    Succeeded,
+    Failed,
+    /// The step declares `paths` and none of the changed files matched them.
+    Skipped,
+}
+
+impl StepStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            StepStatus::Succeeded => "succeeded",
+            StepStatus::Failed => "failed",
+            StepStatus::Skipped => "skipped",
+        }
+    }
+}
+
+/// Whether a step ran for the deploy itself or while restoring the previous commit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Deploy,
+    Rollback,
+}
+
+/// Outcome of one post-deploy pipeline step. Output of steps that ran is in the job's command log.
+#[derive(Clone, Debug, Serialize)]
+pub struct StepResult {
+    pub name: String,
+    pub phase: Phase,
+    pub status: StepStatus,
+    pub exit_code: Option<i32>,
+    pub duration_ms: u64,
+    pub message: Option<String>,
+}
+
+/// One commit brought in by a deploy.
+#[derive(Clone, Debug, Serialize)]
+pub struct CommitSummary {
+    pub sha: String,
+    pub author: String,
+    pub subject: String,
+}
+
+/// Size of the change between the previous and the new HEAD. Binary files count as changed only.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct DiffStat {
+    pub files_changed: u64,
+    pub insertions: u64,
+    pub deletions: u64,
+}
+
+/// Deploy report served by `GET /jobs/{id}`. See README.md for the field reference.
+#[derive(Clone, Debug, Serialize)]
+pub struct Job {
+    #[serde(rename = "job_id")]
+    pub id: Uuid,
+    #[serde(rename = "status")]
+    pub state: JobState,
+    pub outcome: Option<Outcome>,
+    pub target: String,
+    pub trigger: Trigger,
+    pub branch: String,
+    /// Commit the trigger asked for, or the tag of a release.
+    pub commit: String,
+    /// HEAD before the job touched the working tree.
+    pub previous_sha: Option<String>,
+    /// HEAD after the working tree was updated. After a rollback `previous_sha` is live again.
+    pub new_sha: Option<String>,
+    /// Commits in `previous_sha..new_sha`.
+    pub commit_count: Option<u64>,
+    /// The newest commits of `previous_sha..new_sha`, capped at [`crate::git::MAX_LOG_COMMITS`].
+    pub commits: Vec<CommitSummary>,
+    #[serde(flatten)]
+    pub diff: DiffStat,
+    pub steps: Vec<StepResult>,
+    pub health: Option<HealthResult>,
+    /// How a rolled-back job ended up that way.
+    pub message: Option<String>,
+    /// Why the job failed or was cancelled.
+    pub error: Option<ErrorBody>,
+    pub created_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// Time from start to finish; unset until the job finishes.
+    pub duration_ms: Option<u64>,
+    pub commands: Vec<CommandRecord>,
+}
+
+impl Job {
+    fn end(&mut self, state: JobState, log: &mut EventLog) {
+        let now = Utc::now();
+        self.state = state;
+        self.finished_at = Some(now);
+        self.duration_ms = self
+            .started_at
+            .map(|started_at| (now - started_at).num_milliseconds().max(0) as u64);
+        log.emit(JobEvent::Status { status: state });
+    }
+}
+
+/// Everything streamed by `GET /jobs/{id}/stream`, kept for late subscribers.
+#[derive(Default)]
+struct EventLog {
+    events: Vec<Event>,
+    /// Pipeline step currently running, to attribute output lines to.
+    current_step: Option<String>,
+}
+
+impl EventLog {
+    fn emit(&mut self, event: JobEvent) {
+        self.events.push(Event {
+            id: self.events.len(),
+            at: Utc::now(),
+            event,
+        });
+    }
+}
+
+/// Shared handle to a single job, used by the worker running it and by the HTTP handlers.
+pub struct JobHandle {
+    job: Mutex<Job>,
+    /// Always locked after `job`, never before.
+    log: Mutex<EventLog>,
+    cancel: CancellationToken,
+    /// Carries the number of events so far; stream subscribers wait on it.
+    updates: watch::Sender<usize>,
+}
+
+impl JobHandle {
+    pub fn id(&self) -> Uuid {
+        self.job.lock().unwrap().id
+    }
+
+    pub fn state(&self) -> JobState {
+        self.job.lock().unwrap().state
+    }
+
+    pub fn commit(&self) -> String {
+        self.job.lock().unwrap().commit.clone()
+    }
+
+    pub fn target(&self) -> String {
+        self.job.lock().unwrap().target.clone()
+    }
+
+    pub fn trigger(&self) -> Trigger {
+        self.job.lock().unwrap().trigger.clone()
+    }
+
+    pub fn snapshot(&self) -> Job {
+        self.job.lock().unwrap().clone()
+    }
+
+    /// Events from index `from` on, and whether the job has finished.
+    pub fn events_since(&self, from: usize) -> (Vec<Event>, bool) {
+        let job = self.job.lock().unwrap();
+        let log = self.log.lock().unwrap();
+        let events = log.events.get(from..).unwrap_or_default().to_vec();
+        (events, job.state.is_finished())
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<usize> {
+        self.updates.subscribe()
+    }
+
+    /// Runs `update` on the job and its event log, then wakes stream subscribers.
+    fn update<T>(&self, update: impl FnOnce(&mut Job, &mut EventLog) -> T) -> T {
+        let mut job = self.job.lock().unwrap();
+        let mut log = self.log.lock().unwrap();
+        let result = update(&mut job, &mut log);
+        self.updates.send_replace(log.events.len());
+        result
+    }
+
+    /// Fires when the job is cancelled; pipeline steps and read-only git commands select on this
+    /// to kill their process, while git commands that write wait to finish.
+    pub fn cancellation(&self) -> &CancellationToken {
+        &self.cancel
+    }
+
+    /// Moves a queued job to running. Returns `false` if it was cancelled before it got the chance.
+    pub fn start(&self) -> bool {
+        self.update(|job, log| {
+            if job.state != JobState::Queued {
+                return false;
+            }
+            job.state = JobState::Running;
+            job.started_at = Some(Utc::now());
+            log.emit(JobEvent::Status {
+                status: JobState::Running,
+            });
+            true
+        })
+    }
+
+    /// Points a still-queued job at a newer commit on behalf of `trigger`. Returns `false` once it
+    /// has started or was cancelled.
+    pub fn retarget(&self, commit: String, trigger: Trigger) -> bool {
+        let mut job = self.job.lock().unwrap();
+        if job.state != JobState::Queued {
+            return false;
+        }
+        job.commit = commit;
+        job.trigger = trigger;
+        true
+    }
+
+    pub fn record(&self, command: CommandRecord) {
+        self.update(|job, log| {
+            log.emit(JobEvent::CommandFinished {
+                command: command.command.clone(),
+                exit_code: command.exit_code,
+            });
+            job.commands.push(command);
+        });
+    }
+
+    /// Streams one line of output from the command currently running.
+    pub fn record_output(&self, stream: OutputStream, line: String) {
+        self.update(|_, log| {
+            let step = log.current_step.clone();
+            log.emit(JobEvent::Output { step, stream, line });
+        });
+    }
+
+    pub fn record_step_start(&self, step: &str, phase: Phase) {
+        self.update(|_, log| {
+            log.current_step = Some(step.to_string());
+            log.emit(JobEvent::StepStarted {
+                step: step.to_string(),
+                phase,
+            });
+        });
+    }
+
+    pub fn record_step(&self, step: StepResult) {
+        self.update(|job, log| {
+            log.current_step = None;
+            log.emit(JobEvent::StepFinished {
+                step: step.name.clone(),
+                phase: step.phase,
+                status: step.status,
+                exit_code: step.exit_code,
+                duration_ms: step.duration_ms,
+            });
+            job.steps.push(step);
+        });
+    }
+
+    pub fn record_health(&self, health: HealthResult) {
+        self.job.lock().unwrap().health = Some(health);
+    }
+
+    pub fn record_update(&self, previous_sha: Option<String>, new_sha: String, commit_count: Option<u64>) {
+        let mut job = self.job.lock().unwrap();
+        job.outcome = Some(if previous_sha.as_deref() == Some(new_sha.as_str()) {
+            Outcome::NoOp
+        } else {
+            Outcome::Updated
+        });
+        job.previous_sha = previous_sha;
+        job.new_sha = Some(new_sha);
+        job.commit_count = commit_count;
+    }
+
+    pub fn record_changes(&self, commits: Vec<CommitSummary>, diff: DiffStat) {
+        let mut job = self.job.lock().unwrap();
+        job.commits = commits;
+        job.diff = diff;
+    }
+
+    pub fn finish(&self, state: JobState, message: Option<String>) {
+        self.update(|job, log| {
+            if job.state.is_finished() {
+                return;
+            }
+            job.message = message;
+            job.end(state, log);
+        });
+    }
+
+    pub fn fail(&self, state: JobState, error: &Error) {
+        self.update(|job, log| {
+            if job.state.is_finished() {
+                return;
+            }
+            job.error = Some(error.body());
+            job.end(state, log);
+        });
+    }
+
+    /// Cancels a queued or running job. Returns `false` if it had already finished.
+    pub fn cancel(&self) -> bool {
+        let cancelled = self.update(|job, log| {
+            if job.state.is_finished() {
+                return false;
+            }
+            if job.state == JobState::Queued {
+                job.error = Some(Error::Cancelled.body());
+                job.end(JobState::Cancelled, log);
+            }
+            true
+        });
+        if cancelled {
+            self.cancel.cancel();
+        }
+        cancelled
+    }
+}
+
+#[derive(Default)]
+pub struct Jobs {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    jobs: HashMap<Uuid, Arc<JobHandle>>,
+    order: VecDeque<Uuid>,
+}
+
+impl Jobs {
+    pub fn create(&self, target: &Target, commit: String, trigger: Trigger) -> Arc<JobHandle> {
+        let job = Job {
+            id: Uuid::new_v4(),
+            state: JobState::Queued,
+            outcome: None,
+            target: target.name.clone(),
+            trigger,
+            branch: target.branch.clone(),
+            commit,
+            previous_sha: None,
+            new_sha: None,
+            commit_count: None,
+            commits: Vec::new(),
+            diff: DiffStat::default(),
+            steps: Vec::new(),
+            health: None,
+            message: None,
+            error: None,
+            created_at: Utc::now(),
+            started_at: None,
+            finished_at: None,
+            duration_ms: None,
+            commands: Vec::new(),
+        };
+        let mut log = EventLog::default();
+        log.emit(JobEvent::Status {
+            status: JobState::Queued,
+        });
+        let id = job.id;
+        let handle = Arc::new(JobHandle {
+            updates: watch::Sender::new(log.events.len()),
+            job: Mutex::new(job),
+            log: Mutex::new(log),
+            cancel: CancellationToken::new(),
+        });
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.jobs.insert(id, handle.clone());
+        inner.order.push_back(id);
+        inner.prune();
+        handle
+    }
+
+    pub fn get(&self, id: Uuid) -> Option<Arc<JobHandle>> {
+        self.inner.lock().unwrap().jobs.get(&id).cloned()
+    }
+}
+
+impl Inner {
+    fn prune(&mut self) {
+        let mut excess = self.order.len().saturating_sub(MAX_JOBS);
+        let jobs = &mut self.jobs;
+        self.order.retain(|id| {
+            if excess == 0 || !jobs[id].state().is_finished() {
+                return true;
+            }
+            jobs.remove(id);
+            excess -= 1;
+            false
+        });
+    }
+}
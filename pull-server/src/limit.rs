@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::HttpRequest;
+
+use crate::config::{BucketConfig, RateLimitConfig, Target};
+use crate::error::Error;
+use crate::metrics::Metrics;
+
+/// Buckets kept before full ones, which behave like missing ones, are dropped. The count is
+/// exported as `pull_server_rate_limit_buckets`.
+const MAX_BUCKETS: usize = 10000;
+
+/// Token buckets per client IP and per target, so a CI loop cannot keep the deployed checkout
+/// churning and a flood of forged webhooks is turned away early. Each target's cooldown is kept
+/// by its [`DeployQueue`].
+///
+/// [`DeployQueue`]: crate::queue::DeployQueue
+pub struct RateLimiter {
+    clients: Buckets,
+    targets: Buckets,
+    trust_proxy: bool,
+    metrics: Arc<Metrics>,
+}
+
+struct Buckets {
+    /// `client` or `target`, the label of its metrics.
+    scope: &'static str,
+    burst: f64,
+    per_second: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig, metrics: Arc<Metrics>) -> Self {
+        RateLimiter {
+            clients: Buckets::new("client", &config.client),
+            targets: Buckets::new("target", &config.target),
+            trust_proxy: config.trust_proxy,
+            metrics,
+        }
+    }
+
+    /// Takes a token for the requesting client, before the request is authenticated.
+    pub fn check_client(&self, req: &HttpRequest, target: &Target) -> Result<(), Error> {
+        let client = self.client_ip(req);
+        let result = self.clients.take(&client).map_err(|retry_after| Error::RateLimited {
+            scope: format!("from client {}", client),
+            retry_after,
+        });
+        self.record(&self.clients, req, target, &client, result)
+    }
+
+    /// Takes a token for `target`. Only authenticated requests may, or anyone able to reach the
+    /// port could use up the bucket and hold back real deploys.
+    pub fn check_target(&self, req: &HttpRequest, target: &Target) -> Result<(), Error> {
+        let result = self.targets.take(&target.name).map_err(|retry_after| Error::RateLimited {
+            scope: format!("for target {}", target.name),
+            retry_after,
+        });
+        self.record(&self.targets, req, target, &self.client_ip(req), result)
+    }
+
+    /// Updates the metrics of `buckets` and logs a refusal.
+    fn record(
+        &self,
+        buckets: &Buckets,
+        req: &HttpRequest,
+        target: &Target,
+        client: &str,
+        result: Result<(), Error>,
+    ) -> Result<(), Error> {
+        self.metrics.rate_limit_buckets(buckets.scope, buckets.len());
+        if let Err(err) = &result {
+            self.metrics.rate_limited(&target.name, buckets.scope);
+            tracing::warn!(target = %target.name, client = %client, url = %req.uri(), "Rate limited: {}", err);
+        }
+        result
+    }
+
+    fn client_ip(&self, req: &HttpRequest) -> String {
+        if self.trust_proxy {
+            return req.connection_info().realip_remote_addr().unwrap_or("unknown").to_string();
+        }
+        req.peer_addr()
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_else(|| "unknown".to_string())
+    }
+}
+
+impl Buckets {
+    fn new(scope: &'static str, config: &BucketConfig) -> Self {
+        Buckets {
+            scope,
+            burst: f64::from(config.burst),
+            per_second: f64::from(config.per_minute) / 60.0,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token from `key`'s bucket, or says how long until one is available.
+    fn take(&self, key: &str) -> Result<(), Duration> {
+        if self.burst == 0.0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(key) {
+            buckets.retain(|_, bucket| self.refill(bucket, now) < self.burst);
+        }
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let tokens = self.refill(bucket, now);
+        if tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - tokens) / self.per_second));
+        }
+        bucket.tokens = tokens - 1.0;
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.buckets.lock().unwrap().len()
+    }
+
+    /// Adds the tokens regained since the bucket was last touched.
+    fn refill(&self, bucket: &mut Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.burst);
+        bucket.updated = now;
+        bucket.tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use actix_web::test::TestRequest;
+
+    use super::*;
+    use crate::testing;
+
+    fn buckets(burst: u32, per_minute: u32) -> Buckets {
+        Buckets::new("target", &BucketConfig { burst, per_minute })
+    }
+
+    /// Pretends `key`'s bucket was last touched `seconds` ago.
+    fn age(buckets: &Buckets, key: &str, seconds: u64) {
+        let mut buckets = buckets.buckets.lock().unwrap();
+        let bucket = buckets.get_mut(key).unwrap();
+        bucket.updated -= Duration::from_secs(seconds);
+    }
+
+    #[test]
+    fn allows_a_burst_then_one_per_interval() {
+        let buckets = buckets(3, 6);
+        for _ in 0..3 {
+            assert_eq!(buckets.take("web"), Ok(()));
+        }
+        let retry_after = buckets.take("web").unwrap_err();
+        assert!(retry_after > Duration::from_secs(9) && retry_after <= Duration::from_secs(10), "{:?}", retry_after);
+        assert_eq!(buckets.take("api"), Ok(()));
+
+        age(&buckets, "web", 5);
+        let retry_after = buckets.take("web").unwrap_err();
+        assert!(retry_after > Duration::from_secs(4) && retry_after <= Duration::from_secs(5), "{:?}", retry_after);
+        age(&buckets, "web", 5);
+        assert_eq!(buckets.take("web"), Ok(()));
+        assert!(buckets.take("web").is_err());
+    }
+
+    #[test]
+    fn refills_up_to_the_burst() {
+        let buckets = buckets(2, 60);
+        assert_eq!(buckets.take("web"), Ok(()));
+        assert_eq!(buckets.take("web"), Ok(()));
+        age(&buckets, "web", 3600);
+        assert_eq!(buckets.take("web"), Ok(()));
+        assert_eq!(buckets.take("web"), Ok(()));
+        assert!(buckets.take("web").is_err());
+    }
+
+    #[test]
+    fn zero_burst_is_unlimited() {
+        let buckets = buckets(0, 0);
+        for _ in 0..100 {
+            assert_eq!(buckets.take("web"), Ok(()));
+        }
+        assert!(buckets.buckets.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn drops_full_buckets_once_there_are_too_many() {
+        let buckets = buckets(2, 60);
+        for n in 0..MAX_BUCKETS {
+            assert_eq!(buckets.take(&n.to_string()), Ok(()));
+        }
+        assert_eq!(buckets.take("0"), Ok(()));
+        age(&buckets, "1", 60);
+        assert_eq!(buckets.take("new"), Ok(()));
+        let buckets = buckets.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), MAX_BUCKETS);
+        assert!(!buckets.contains_key("1"));
+        assert!(buckets.contains_key("0"));
+    }
+
+    #[test]
+    fn trusts_forwarded_addresses_only_if_configured() {
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.2:4000".parse().unwrap())
+            .insert_header(("X-Forwarded-For", "203.0.113.7"))
+            .to_http_request();
+        let limiter = |trust_proxy| {
+            let config = RateLimitConfig {
+                client: BucketConfig { burst: 1, per_minute: 1 },
+                target: BucketConfig { burst: 1, per_minute: 1 },
+                trust_proxy,
+            };
+            RateLimiter::new(&config, Arc::new(Metrics::new()))
+        };
+        assert_eq!(limiter(false).client_ip(&req), "10.0.0.2");
+        assert_eq!(limiter(true).client_ip(&req), "203.0.113.7");
+    }
+
+    #[test]
+    fn counts_refusals_and_buckets_by_scope() {
+        let metrics = Arc::new(Metrics::new());
+        let limiter = RateLimiter::new(
+            &RateLimitConfig {
+                client: BucketConfig { burst: 2, per_minute: 1 },
+                target: BucketConfig { burst: 1, per_minute: 1 },
+                trust_proxy: false,
+            },
+            metrics.clone(),
+        );
+        let target = testing::target(Path::new("/srv/web"));
+        let from = |peer: &str| TestRequest::default().peer_addr(peer.parse().unwrap()).to_http_request();
+
+        assert!(limiter.check_client(&from("10.0.0.2:4000"), &target).is_ok());
+        assert!(limiter.check_client(&from("10.0.0.3:4000"), &target).is_ok());
+        assert!(limiter.check_target(&from("10.0.0.3:4000"), &target).is_ok());
+        assert!(limiter.check_target(&from("10.0.0.3:4000"), &target).is_err());
+        assert!(limiter.check_client(&from("10.0.0.3:4000"), &target).is_ok());
+        assert!(limiter.check_client(&from("10.0.0.3:4000"), &target).is_err());
+
+        let text = metrics.render();
+        for line in [
+            r#"pull_server_rate_limited_total{scope="client",target="web"} 1"#,
+            r#"pull_server_rate_limited_total{scope="target",target="web"} 1"#,
+            r#"pull_server_rate_limit_buckets{scope="client"} 2"#,
+            r#"pull_server_rate_limit_buckets{scope="target"} 1"#,
+        ] {
+            assert!(text.contains(line), "{} missing from\n{}", line, text);
+        }
+    }
+}
@@ -0,0 +1,283 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::{Local, NaiveDate};
+use serde_json::{Map, Value};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::FormatTime;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, MakeWriter};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+use crate::config::LogConfig;
+
+/// Sends logs to stderr and to `deploy-YYYY-MM-DD.log` in the configured directory, which is
+/// meant to be the backend's `logs/` so the admin log viewer lists them.
+pub fn init(config: &LogConfig) -> io::Result<()> {
+    let filter = EnvFilter::try_new(&config.level).map_err(io::Error::other)?;
+    let file = DailyFile::open(&config.dir, config.keep_days)?;
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer().event_format(WinstonFormat(LocalTime)).with_writer(io::stderr))
+        .with(tracing_subscriber::fmt::layer().event_format(WinstonFormat(LocalTime)).with_writer(file))
+        .init();
+    Ok(())
+}
+
+/// Logs to stderr alone, for errors that happen before the configuration is known.
+pub fn init_stderr() {
+    tracing_subscriber::fmt()
+        .event_format(WinstonFormat(LocalTime))
+        .with_writer(io::stderr)
+        .init();
+}
+
+/// The line layout of the backend's winston logger (src/utils/logger.mjs):
+/// `2026-10-17 14:02:11 info: message {"field":"value"}`, with the fields as one JSON object.
+/// The timestamp comes from `T`.
+struct WinstonFormat<T>(T);
+
+impl<S, N, T> FormatEvent<S, N> for WinstonFormat<T>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    T: FormatTime,
+{
+    fn format_event(&self, _ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        self.0.format_time(&mut writer)?;
+        write!(writer, " {}: {}", level_name(*event.metadata().level()), fields.message)?;
+        if !fields.rest.is_empty() {
+            write!(writer, " {}", Value::Object(fields.rest))?;
+        }
+        writeln!(writer)
+    }
+}
+
+/// The local time as winston's `YYYY-MM-DD HH:mm:ss`.
+struct LocalTime;
+
+impl FormatTime for LocalTime {
+    fn format_time(&self, writer: &mut Writer<'_>) -> fmt::Result {
+        write!(writer, "{}", Local::now().format("%Y-%m-%d %H:%M:%S"))
+    }
+}
+
+/// winston's levels are error, warn, info and verbose.
+fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::ERROR => "error",
+        Level::WARN => "warn",
+        Level::INFO => "info",
+        Level::DEBUG | Level::TRACE => "verbose",
+    }
+}
+
+#[derive(Default)]
+struct Fields {
+    message: String,
+    rest: Map<String, Value>,
+}
+
+impl Fields {
+    fn insert(&mut self, field: &Field, value: Value) {
+        if field.name() == "message" {
+            self.message = match value {
+                Value::String(message) => message,
+                value => value.to_string(),
+            };
+        } else {
+            self.rest.insert(field.name().to_string(), value);
+        }
+    }
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        // JSON.stringify writes whole numbers without a fraction: 1, not 1.0.
+        if value.fract() == 0.0 && value.abs() < 1e15 {
+            self.insert(field, Value::from(value as i64));
+        } else {
+            self.insert(field, Value::from(value));
+        }
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        self.insert(field, Value::from(value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, Value::from(format!("{:?}", value)));
+    }
+}
+
+/// Appends to `deploy-YYYY-MM-DD.log` for the current local date, switching files at midnight
+/// and deleting all but the newest `keep` of them.
+struct DailyFile {
+    dir: PathBuf,
+    keep: usize,
+    current: Mutex<(NaiveDate, File)>,
+}
+
+impl DailyFile {
+    fn open(dir: &Path, keep: usize) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let today = Local::now().date_naive();
+        let file = open_log(dir, today)?;
+        let daily = DailyFile {
+            dir: dir.to_path_buf(),
+            keep,
+            current: Mutex::new((today, file)),
+        };
+        daily.prune();
+        Ok(daily)
+    }
+
+    fn prune(&self) {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return;
+        };
+        let mut logs: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .and_then(|name| name.strip_prefix("deploy-")?.strip_suffix(".log"))
+                    .is_some_and(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").is_ok())
+            })
+            .collect();
+        // The date format sorts chronologically.
+        logs.sort();
+        let excess = logs.len().saturating_sub(self.keep.max(1));
+        for path in &logs[..excess] {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+fn open_log(dir: &Path, date: NaiveDate) -> io::Result<File> {
+    let path = dir.join(format!("deploy-{}.log", date.format("%Y-%m-%d")));
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl<'a> MakeWriter<'a> for DailyFile {
+    type Writer = DailyWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        DailyWriter(self)
+    }
+}
+
+struct DailyWriter<'a>(&'a DailyFile);
+
+impl Write for DailyWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let today = Local::now().date_naive();
+        let mut current = self.0.current.lock().unwrap();
+        if current.0 != today {
+            *current = (today, open_log(&self.0.dir, today)?);
+            drop(current);
+            self.0.prune();
+            current = self.0.current.lock().unwrap();
+        }
+        current.1.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.current.lock().unwrap().1.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn fixed(writer: &mut Writer<'_>) -> fmt::Result {
+        write!(writer, "2026-10-17 14:02:11")
+    }
+
+    fn capture(log: impl FnOnce()) -> String {
+        let buffer = Buffer::default();
+        let output = buffer.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .event_format(WinstonFormat(fixed as fn(&mut Writer<'_>) -> fmt::Result))
+            .with_max_level(Level::TRACE)
+            .with_writer(move || output.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, log);
+        String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap()
+    }
+
+    #[test]
+    fn formats_lines_like_the_backend_logger() {
+        // What src/utils/logger.mjs writes for logger.info("Deployed web to abc1234",
+        // { job: "4f1c", attempt: 2, ratio: 1, ok: true, note: "설명 \"q\"\n" }).
+        let line = capture(|| {
+            let note = "설명 \"q\"\n";
+            let ratio = 1.0;
+            tracing::info!(job = "4f1c", attempt = 2, ratio, ok = true, note, "Deployed {} to {}", "web", "abc1234")
+        });
+        assert_eq!(
+            line,
+            "2026-10-17 14:02:11 info: Deployed web to abc1234 \
+             {\"job\":\"4f1c\",\"attempt\":2,\"ratio\":1,\"ok\":true,\"note\":\"설명 \\\"q\\\"\\n\"}\n"
+        );
+    }
+
+    #[test]
+    fn leaves_out_empty_fields_and_maps_levels() {
+        let lines = capture(|| {
+            tracing::error!("Deploy failed");
+            tracing::warn!(ratio = 0.25, "Slow");
+            tracing::debug!("Fetching");
+            tracing::trace!("Polling");
+        });
+        assert_eq!(
+            lines,
+            "2026-10-17 14:02:11 error: Deploy failed\n\
+             2026-10-17 14:02:11 warn: Slow {\"ratio\":0.25}\n\
+             2026-10-17 14:02:11 verbose: Fetching\n\
+             2026-10-17 14:02:11 verbose: Polling\n"
+        );
+    }
+}
@@ -1,30 +1,584 @@
-use std::process::Command;
-use actix_web::{get, Responder, App, HttpResponse, HttpServer};
+mod cli;
+mod config;
+mod deploy;
+mod error;
+mod events;
+mod git;
+mod github;
+mod health;
+mod history;
+mod jobs;
+mod limit;
+mod logging;
+mod metrics;
+mod notify;
+mod pipeline;
+mod process;
+mod queue;
+mod replay;
+mod rollback;
+#[cfg(test)]
+mod testing;
+mod webhook;
+
+use std::sync::Arc;
+
+use actix_web::{delete, get, post, web, App, HttpRequest, HttpResponse, HttpServer};
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use cli::Cli;
+use config::{Config, DeployMode, Target, DEFAULT_TARGET};
+use error::Error;
+use github::GitHub;
+use history::{Filter, History};
+use jobs::{Jobs, Trigger, TriggerSource};
+use limit::RateLimiter;
+use metrics::Metrics;
+use notify::Notifier;
+use queue::{DeployQueue, Queues, Submitted};
+use replay::{Claim, ReplayGuard};
+use rollback::RollbackRequest;
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+use webhook::{Hook, PushEvent};
+
+/// GitHub caps webhook payloads at 25 MB.
+const MAX_PAYLOAD_SIZE: usize = 25 * 1024 * 1024;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    HttpServer::new(|| App::new().service(index))
-        .bind(("127.0.0.1", 10000))?
-        .run()
-        .await
+    let cli = Cli::parse();
+    let config = match Config::load(&cli) {
+        Ok(config) => Arc::new(config),
+        Err(err) => {
+            logging::init_stderr();
+            tracing::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    if cli.check_config {
+        println!("Configuration OK\n{}", config.describe());
+        return Ok(());
+    }
+    if let Err(err) = logging::init(&config.log) {
+        logging::init_stderr();
+        tracing::error!(dir = %config.log.dir.display(), "Failed to open the log directory: {}", err);
+        std::process::exit(1);
+    }
+    let history = match History::open(&config.history) {
+        Ok(history) => Arc::new(history),
+        Err(err) => {
+            tracing::error!(path = %config.history.display(), "Failed to open deploy history: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let replay = match ReplayGuard::open(&config.replay) {
+        Ok(replay) => web::Data::new(replay),
+        Err(err) => {
+            tracing::error!(path = %config.replay.store.display(), "Failed to open the delivery store: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let metrics = Arc::new(Metrics::new());
+    let limiter = web::Data::new(RateLimiter::new(&config.rate_limit, metrics.clone()));
+    let notifier = match Notifier::new(config.notify.clone()) {
+        Ok(notifier) => Arc::new(notifier),
+        Err(err) => {
+            tracing::error!("Failed to build the notification HTTP client: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let github = match config.github.as_ref().map(GitHub::new).transpose() {
+        Ok(github) => github.map(Arc::new),
+        Err(err) => {
+            tracing::error!("Failed to build the GitHub HTTP client: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let queues: Queues = config
+        .targets
+        .iter()
+        .map(|(name, target)| {
+            let queue = DeployQueue::new(
+                target.clone(),
+                history.clone(),
+                metrics.clone(),
+                notifier.clone(),
+                github.clone(),
+            );
+            (name.clone(), Arc::new(queue))
+        })
+        .collect();
+    for queue in queues.values() {
+        queue.refresh_deployed().await;
+    }
+    let queues = web::Data::new(queues);
+    let metrics = web::Data::from(metrics);
+    let history = web::Data::from(history);
+    let jobs = web::Data::new(Jobs::default());
+
+    HttpServer::new(move || {
+        App::new()
+            .app_data(jobs.clone())
+            .app_data(queues.clone())
+            .app_data(history.clone())
+            .app_data(metrics.clone())
+            .app_data(replay.clone())
+            .app_data(limiter.clone())
+            .app_data(web::PayloadConfig::new(MAX_PAYLOAD_SIZE))
+            .app_data(
+                web::QueryConfig::default().error_handler(|err, _| Error::InvalidQuery(err.to_string()).into()),
+            )
+            .service(index)
+            .service(deploy_target)
+            .service(rollback_target)
+            .service(job_status)
+            .service(job_stream)
+            .service(deploys)
+            .service(metrics_endpoint)
+            .service(cancel_job)
+    })
+    .bind((config.bind.as_str(), config.port))?
+    .run()
+    .await
+}
+
+/// Deploys the [`DEFAULT_TARGET`], for single-target setups and existing webhooks.
+#[post("/")]
+async fn index(
+    req: HttpRequest,
+    body: web::Bytes,
+    jobs: web::Data<Jobs>,
+    queues: web::Data<Queues>,
+    metrics: web::Data<Metrics>,
+    replay: web::Data<ReplayGuard>,
+    limiter: web::Data<RateLimiter>,
+) -> Result<HttpResponse, Error> {
+    deploy(req, body, None, jobs, queues, metrics, replay, limiter).await
+}
+
+#[post("/deploy/{target}")]
+#[allow(clippy::too_many_arguments)]
+async fn deploy_target(
+    req: HttpRequest,
+    body: web::Bytes,
+    target: web::Path<String>,
+    jobs: web::Data<Jobs>,
+    queues: web::Data<Queues>,
+    metrics: web::Data<Metrics>,
+    replay: web::Data<ReplayGuard>,
+    limiter: web::Data<RateLimiter>,
+) -> Result<HttpResponse, Error> {
+    deploy(req, body, Some(target.into_inner()), jobs, queues, metrics, replay, limiter).await
 }
 
-#[get("/")]
-async fn index() -> impl Responder {
-    let output = Command::new("git")
-        .arg("pull")
-        .output()
-        .expect("Failed to execute command");
+/// Handles a webhook for `target`, or for the [`DEFAULT_TARGET`] if `None`.
+#[allow(clippy::too_many_arguments)]
+async fn deploy(
+    req: HttpRequest,
+    body: web::Bytes,
+    target: Option<String>,
+    jobs: web::Data<Jobs>,
+    queues: web::Data<Queues>,
+    metrics: web::Data<Metrics>,
+    replay: web::Data<ReplayGuard>,
+    limiter: web::Data<RateLimiter>,
+) -> Result<HttpResponse, Error> {
+    let queue = find_queue(&queues, target.as_deref().unwrap_or(DEFAULT_TARGET))?;
+    let result = limiter
+        .check_client(&req, queue.target())
+        .and_then(|()| webhook(&req, &body, queue, &jobs, &replay, &limiter));
+    respond(&metrics, queue.target(), TriggerSource::Push, result)
+}
 
-    let output_text = String::from_utf8_lossy(&output.stdout).trim().to_string();
+/// Redeploys an earlier commit, signed like a webhook with the target's secret.
+#[post("/deploy/{target}/rollback")]
+#[allow(clippy::too_many_arguments)]
+async fn rollback_target(
+    req: HttpRequest,
+    body: web::Bytes,
+    target: web::Path<String>,
+    jobs: web::Data<Jobs>,
+    queues: web::Data<Queues>,
+    history: web::Data<History>,
+    metrics: web::Data<Metrics>,
+    replay: web::Data<ReplayGuard>,
+    limiter: web::Data<RateLimiter>,
+) -> Result<HttpResponse, Error> {
+    let queue = find_queue(&queues, &target)?;
+    let result = match limiter.check_client(&req, queue.target()) {
+        Ok(()) => rollback(&req, &body, queue, &jobs, &history, &replay, &limiter).await,
+        Err(err) => Err(err),
+    };
+    respond(&metrics, queue.target(), TriggerSource::Rollback, result)
+}
+
+fn find_queue<'a>(queues: &'a Queues, name: &str) -> Result<&'a Arc<DeployQueue>, Error> {
+    queues.get(name).ok_or_else(|| Error::TargetNotFound(name.to_string()))
+}
+
+/// What became of a deploy request.
+enum Triggered {
+    /// A GitHub ping, answered with the target's configuration.
+    Ping,
+    /// The request was valid but does not call for a deploy.
+    Skipped(String),
+    Submitted(Submitted),
+}
+
+/// Counts a deploy request in the metrics and turns its outcome into the response. `source` is
+/// the label for requests that queued nothing; queued ones are counted under their trigger.
+fn respond(
+    metrics: &Metrics,
+    target: &Target,
+    source: TriggerSource,
+    result: Result<Triggered, Error>,
+) -> Result<HttpResponse, Error> {
+    let (source, label) = match &result {
+        Ok(Triggered::Ping) => (source, "ping"),
+        Ok(Triggered::Skipped(_)) => (source, "skipped"),
+        Ok(Triggered::Submitted(submitted)) => (submitted.job().trigger().source, submitted.status()),
+        Err(err) => (source, err.kind()),
+    };
+    metrics.trigger(&target.name, source, label);
+    Ok(match result? {
+        Triggered::Ping => HttpResponse::Ok().json(json!({
+            "status": "pong",
+            "target": target.name,
+            "provider": target.provider.name(),
+            "branch": target.branch,
+            "remote": target.remote,
+            "mode": target.mode.name(),
+            "steps": target.steps.iter().map(|step| step.name.as_str()).collect::<Vec<_>>(),
+            "health_url": target.health.as_ref().map(|health| health.url.as_str()),
+            "github_repository": target.github.as_ref().map(|github| github.repository.as_str()),
+        })),
+        Triggered::Skipped(reason) => HttpResponse::Accepted().json(json!({
+            "status": "skipped",
+            "reason": reason,
+        })),
+        Triggered::Submitted(submitted) => {
+            let job = submitted.job();
+            HttpResponse::Accepted().json(json!({
+                "status": submitted.status(),
+                "job_id": job.id(),
+                "target": target.name,
+                "branch": target.branch,
+                "commit": job.commit(),
+                "status_url": format!("/jobs/{}", job.id()),
+            }))
+        }
+    })
+}
+
+/// Verifies a webhook and queues the deploy it calls for, if any.
+fn webhook(
+    req: &HttpRequest,
+    body: &[u8],
+    queue: &Arc<DeployQueue>,
+    jobs: &Jobs,
+    replay: &ReplayGuard,
+    limiter: &RateLimiter,
+) -> Result<Triggered, Error> {
+    let target = queue.target();
+    let provider = target.provider;
+    if !provider.verify(&target.secret, req.headers(), body) {
+        return Err(reject(req, target, provider.auth_header()));
+    }
+    limiter.check_target(req, target)?;
+    let delivery = provider.delivery_id(req.headers());
+    let claim = match replay.check(delivery, provider.sent_at(req.headers(), body)) {
+        Ok(claim) => claim,
+        Err(err) => {
+            tracing::warn!(target = %target.name, delivery = delivery.unwrap_or("none"), "Rejected webhook: {}", err);
+            return Err(err);
+        }
+    };
+    // A delivery refused from here on may be redelivered, so it is only remembered once accepted.
+    let triggered = dispatch(req, body, queue, jobs)?;
+    claim.remember();
+    Ok(triggered)
+}
+
+/// Acts on a verified webhook according to its event.
+fn dispatch(req: &HttpRequest, body: &[u8], queue: &Arc<DeployQueue>, jobs: &Jobs) -> Result<Triggered, Error> {
+    let target = queue.target();
+    match target.provider.parse(req.headers(), body, &target.branch)? {
+        Hook::Ping => {
+            tracing::info!(target = %target.name, "Answered a webhook ping");
+            Ok(Triggered::Ping)
+        }
+        Hook::Push(event) => push(req, event, queue, jobs),
+        Hook::WorkflowRun {
+            workflow,
+            path,
+            branch,
+            sha,
+            actor,
+        } => {
+            if !target.workflows.iter().any(|name| *name == workflow || *name == path) {
+                return Ok(Triggered::Skipped(format!(
+                    "workflow {} ({}) is not one of the target's workflows",
+                    workflow, path
+                )));
+            }
+            if branch != target.branch {
+                return Ok(Triggered::Skipped(format!(
+                    "workflow run {} ran on {}, not the deployed branch {}",
+                    workflow, branch, target.branch
+                )));
+            }
+            if !git::is_sha(&sha) {
+                return Err(Error::InvalidPayload(format!("Invalid commit SHA: {}", sha)));
+            }
+            let submitted = submit(req, queue, jobs, sha, TriggerSource::WorkflowRun, actor)?;
+            let job = submitted.job();
+            tracing::info!(
+                job_id = %job.id(),
+                target = %target.name,
+                commit = %job.commit(),
+                "Job {} for workflow run {}",
+                submitted.status(),
+                workflow
+            );
+            Ok(Triggered::Submitted(submitted))
+        }
+        Hook::Release {
+            tag,
+            prerelease,
+            author,
+        } => {
+            if !target.releases {
+                return Ok(Triggered::Skipped(format!(
+                    "release {} was published, but the target does not deploy releases",
+                    tag
+                )));
+            }
+            if prerelease && !target.prereleases {
+                return Ok(Triggered::Skipped(format!("release {} is a prerelease", tag)));
+            }
+            if !git::is_tag_name(&tag) {
+                return Err(Error::InvalidPayload(format!("Invalid tag name: {}", tag)));
+            }
+            let submitted = submit(req, queue, jobs, tag, TriggerSource::Release, author)?;
+            let job = submitted.job();
+            tracing::info!(
+                job_id = %job.id(),
+                target = %target.name,
+                "Job {} for release {}",
+                submitted.status(),
+                job.commit()
+            );
+            Ok(Triggered::Submitted(submitted))
+        }
+        Hook::Ignored(reason) => Ok(Triggered::Skipped(reason)),
+    }
+}
 
-    if output.status.success() {
-        if !output_text.contains("Already up to date.") {
-            HttpResponse::Ok().body(format!("Changes applied: {}", output_text))
-        } else {
-            HttpResponse::AlreadyReported().body("No changes to apply")
+/// Queues a deploy of the pushed commit if the push was to the deployed branch.
+fn push(req: &HttpRequest, event: PushEvent, queue: &Arc<DeployQueue>, jobs: &Jobs) -> Result<Triggered, Error> {
+    let target = queue.target();
+    if event.branch() != Some(target.branch.as_str()) {
+        return Ok(Triggered::Skipped(format!(
+            "{} is not the deployed branch refs/heads/{}",
+            event.git_ref, target.branch
+        )));
+    }
+    if target.mode != DeployMode::Pull {
+        if !git::is_sha(&event.after) {
+            return Err(Error::InvalidPayload(format!("Invalid commit SHA: {}", event.after)));
         }
-    } else {
-        HttpResponse::InternalServerError().body(format!("Error applying changes: {}", String::from_utf8_lossy(&output.stderr).trim()))
+        if event.after.bytes().all(|byte| byte == b'0') {
+            return Ok(Triggered::Skipped(format!("{} was deleted", event.git_ref)));
+        }
+    }
+
+    let submitted = submit(req, queue, jobs, event.after.clone(), TriggerSource::Push, event.pusher.clone())?;
+    let job = submitted.job();
+    tracing::info!(
+        job_id = %job.id(),
+        target = %target.name,
+        repository = %event.repository,
+        before = %event.before,
+        commit = %event.after,
+        commits = event.commits.len(),
+        pusher = %event.pusher,
+        "Job {} for {}",
+        submitted.status(),
+        event.git_ref
+    );
+    for commit in &event.commits {
+        tracing::debug!(
+            job_id = %job.id(),
+            commit = %commit.id,
+            author = %commit.author,
+            "Pushed {}",
+            commit.message.lines().next().unwrap_or_default()
+        );
+    }
+    Ok(Triggered::Submitted(submitted))
+}
+
+/// Queues a deploy of `commit` requested by a webhook.
+fn submit(
+    req: &HttpRequest,
+    queue: &Arc<DeployQueue>,
+    jobs: &Jobs,
+    commit: String,
+    source: TriggerSource,
+    requester: String,
+) -> Result<Submitted, Error> {
+    let target = queue.target();
+    if !target.git_dir.is_dir() {
+        return Err(Error::RepoNotFound {
+            path: target.git_dir.clone(),
+        });
+    }
+    let trigger = Trigger {
+        source,
+        requester: Some(requester),
+        remote_addr: req.peer_addr().map(|addr| addr.ip().to_string()),
+    };
+    Ok(queue.submit(jobs, commit, trigger))
+}
+
+async fn rollback(
+    req: &HttpRequest,
+    body: &[u8],
+    queue: &Arc<DeployQueue>,
+    jobs: &Jobs,
+    history: &History,
+    replay: &ReplayGuard,
+    limiter: &RateLimiter,
+) -> Result<Triggered, Error> {
+    let target = queue.target();
+    authenticate(req, body, target)?;
+    limiter.check_target(req, target)?;
+
+    let request: RollbackRequest =
+        serde_json::from_slice(body).map_err(|err| Error::InvalidPayload(err.to_string()))?;
+    let (Some(nonce), Some(timestamp)) = (&request.nonce, request.timestamp) else {
+        return Err(Error::InvalidPayload("nonce and timestamp are required".to_string()));
+    };
+    let claim = claim_nonce(replay, target, "rollback", nonce, timestamp)?;
+    let commit = rollback::resolve(target, history, &request).await?;
+
+    let trigger = Trigger {
+        source: TriggerSource::Rollback,
+        requester: request.requester,
+        remote_addr: req.peer_addr().map(|addr| addr.ip().to_string()),
+    };
+    let submitted = queue.submit(jobs, commit.clone(), trigger);
+    claim.remember();
+    let job = submitted.job();
+    tracing::info!(
+        job_id = %job.id(),
+        target = %target.name,
+        commit = %commit,
+        "Job {} for a rollback",
+        submitted.status()
+    );
+    Ok(Triggered::Submitted(submitted))
+}
+
+/// Holds the nonce of a signed `action` request against replays, as [`ReplayGuard`] does for
+/// webhook delivery IDs.
+fn claim_nonce<'a>(
+    replay: &'a ReplayGuard,
+    target: &Target,
+    action: &str,
+    nonce: &str,
+    timestamp: DateTime<Utc>,
+) -> Result<Claim<'a>, Error> {
+    replay::check_nonce(nonce).inspect_err(|err| {
+        tracing::warn!(target = %target.name, "Rejected {}: {}", action, err);
+    })?;
+    let key = format!("{}/{}/{}", action, target.name, nonce);
+    replay.check(Some(&key), Some(timestamp)).inspect_err(|err| {
+        tracing::warn!(target = %target.name, "Rejected {}: {}", action, err);
+    })
+}
+
+/// Checks the `X-Hub-Signature-256` of a request for `target`, whichever forge its webhooks
+/// come from.
+fn authenticate(req: &HttpRequest, body: &[u8], target: &Target) -> Result<(), Error> {
+    let signature = req.headers().get(webhook::SIGNATURE_HEADER).and_then(|value| value.to_str().ok());
+    if webhook::verify_signature(&target.secret, body, signature) {
+        return Ok(());
+    }
+    Err(reject(req, target, webhook::SIGNATURE_HEADER))
+}
+
+fn reject(req: &HttpRequest, target: &Target, header: &str) -> Error {
+    let peer = req.peer_addr().map(|addr| addr.to_string()).unwrap_or_else(|| "unknown".to_string());
+    tracing::warn!(target = %target.name, peer = %peer, "Rejected request: invalid or missing {}", header);
+    Error::InvalidSignature
+}
+
+#[get("/jobs/{id}")]
+async fn job_status(id: web::Path<Uuid>, jobs: web::Data<Jobs>) -> Result<HttpResponse, Error> {
+    let job = jobs.get(id.into_inner()).ok_or(Error::JobNotFound)?;
+    Ok(HttpResponse::Ok().json(job.snapshot()))
+}
+
+/// Live events of a job as Server-Sent Events, starting with everything that already happened.
+/// A reconnecting client's `Last-Event-ID` resumes after that event.
+#[get("/jobs/{id}/stream")]
+async fn job_stream(req: HttpRequest, id: web::Path<Uuid>, jobs: web::Data<Jobs>) -> Result<HttpResponse, Error> {
+    let job = jobs.get(id.into_inner()).ok_or(Error::JobNotFound)?;
+    let from = events::resume_from(req.headers());
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        // Keeps nginx from buffering the stream.
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(events::stream(job, from)))
+}
+
+#[get("/deploys")]
+async fn deploys(filter: web::Query<Filter>, history: web::Data<History>) -> Result<HttpResponse, Error> {
+    let deploys = history.query(&filter).await.map_err(Error::History)?;
+    Ok(HttpResponse::Ok().json(json!({ "deploys": deploys })))
+}
+
+#[get("/metrics")]
+async fn metrics_endpoint(metrics: web::Data<Metrics>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.render())
+}
+
+/// Body of `DELETE /jobs/{id}`, signed with the secret of the job's target.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CancelRequest {
+    nonce: String,
+    timestamp: DateTime<Utc>,
+}
+
+/// Cancels a job. Commands that write to the repository are let finish first, so a cancelled
+/// deploy never leaves a held index lock or a half-updated tree behind.
+#[delete("/jobs/{id}")]
+async fn cancel_job(
+    req: HttpRequest,
+    body: web::Bytes,
+    id: web::Path<Uuid>,
+    jobs: web::Data<Jobs>,
+    queues: web::Data<Queues>,
+    replay: web::Data<ReplayGuard>,
+) -> Result<HttpResponse, Error> {
+    let job = jobs.get(id.into_inner()).ok_or(Error::JobNotFound)?;
+    let queue = find_queue(&queues, &job.target())?;
+    let target = queue.target();
+    authenticate(&req, &body, target)?;
+    let request: CancelRequest =
+        serde_json::from_slice(&body).map_err(|err| Error::InvalidPayload(err.to_string()))?;
+    let claim = claim_nonce(&replay, target, "cancel", &request.nonce, request.timestamp)?;
+    if !job.cancel() {
+        return Err(Error::JobFinished);
     }
+    claim.remember();
+    tracing::info!(job_id = %job.id(), target = %target.name, "Cancelling the job");
+    Ok(HttpResponse::Accepted().json(job.snapshot()))
 }
@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+use crate::jobs::{Job, JobState, StepStatus, TriggerSource};
+
+/// Deploys and steps range from a no-op fetch to a cold `bun install`.
+const DURATION_BUCKETS: &[f64] = &[0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0];
+
+/// Counters and gauges served at `GET /metrics` in the Prometheus text format.
+pub struct Metrics {
+    registry: Registry,
+    triggers: IntCounterVec,
+    deploys: IntCounterVec,
+    deploy_duration: HistogramVec,
+    step_duration: HistogramVec,
+    deployed_commit: IntGaugeVec,
+    queue_depth: IntGaugeVec,
+    last_success: GaugeVec,
+    rate_limited: IntCounterVec,
+    rate_limit_buckets: IntGaugeVec,
+    /// SHA currently labelled in `deployed_commit`, per target, so the old series can be dropped.
+    deployed: Mutex<HashMap<String, String>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let triggers = IntCounterVec::new(
+            Opts::new("pull_server_triggers_total", "Deploy requests by source and what became of them"),
+            &["target", "source", "result"],
+        )
+        .unwrap();
+        let deploys = IntCounterVec::new(
+            Opts::new("pull_server_deploys_total", "Finished deploy jobs by final status"),
+            &["target", "status"],
+        )
+        .unwrap();
+        let deploy_duration = HistogramVec::new(
+            HistogramOpts::new("pull_server_deploy_duration_seconds", "Time from start to finish of deploy jobs")
+                .buckets(DURATION_BUCKETS.to_vec()),
+            &["target", "status"],
+        )
+        .unwrap();
+        let step_duration = HistogramVec::new(
+            HistogramOpts::new("pull_server_step_duration_seconds", "Run time of pipeline steps that ran")
+                .buckets(DURATION_BUCKETS.to_vec()),
+            &["target", "step", "status"],
+        )
+        .unwrap();
+        let deployed_commit = IntGaugeVec::new(
+            Opts::new("pull_server_deployed_commit", "Always 1; the sha label is the commit live on the target"),
+            &["target", "sha"],
+        )
+        .unwrap();
+        let queue_depth = IntGaugeVec::new(
+            Opts::new("pull_server_queue_depth", "Running plus waiting deploy jobs"),
+            &["target"],
+        )
+        .unwrap();
+        let last_success = GaugeVec::new(
+            Opts::new(
+                "pull_server_last_success_timestamp_seconds",
+                "Unix time the last successful deploy finished",
+            ),
+            &["target"],
+        )
+        .unwrap();
+
+        let rate_limited = IntCounterVec::new(
+            Opts::new("pull_server_rate_limited_total", "Requests refused by a client or target rate limit"),
+            &["target", "scope"],
+        )
+        .unwrap();
+        let rate_limit_buckets = IntGaugeVec::new(
+            Opts::new("pull_server_rate_limit_buckets", "Token buckets the rate limiter keeps, by scope"),
+            &["scope"],
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(triggers.clone())).unwrap();
+        registry.register(Box::new(deploys.clone())).unwrap();
+        registry.register(Box::new(deploy_duration.clone())).unwrap();
+        registry.register(Box::new(step_duration.clone())).unwrap();
+        registry.register(Box::new(deployed_commit.clone())).unwrap();
+        registry.register(Box::new(queue_depth.clone())).unwrap();
+        registry.register(Box::new(last_success.clone())).unwrap();
+        registry.register(Box::new(rate_limited.clone())).unwrap();
+        registry.register(Box::new(rate_limit_buckets.clone())).unwrap();
+
+        Metrics {
+            registry,
+            triggers,
+            deploys,
+            deploy_duration,
+            step_duration,
+            deployed_commit,
+            queue_depth,
+            last_success,
+            rate_limited,
+            rate_limit_buckets,
+            deployed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a deploy request. `result` is the submission status, `skipped` or an error kind.
+    pub fn trigger(&self, target: &str, source: TriggerSource, result: &str) {
+        self.triggers
+            .with_label_values(&[target, source.as_str(), result])
+            .inc();
+    }
+
+    pub fn queue_depth(&self, target: &str, depth: usize) {
+        self.queue_depth.with_label_values(&[target]).set(depth as i64);
+    }
+
+    /// Counts a request refused by the `scope` (`client` or `target`) rate limit.
+    pub fn rate_limited(&self, target: &str, scope: &str) {
+        self.rate_limited.with_label_values(&[target, scope]).inc();
+    }
+
+    pub fn rate_limit_buckets(&self, scope: &str, count: usize) {
+        self.rate_limit_buckets.with_label_values(&[scope]).set(count as i64);
+    }
+
+    /// Marks `sha` as the commit live on `target`.
+    pub fn deployed(&self, target: &str, sha: &str) {
+        let mut deployed = self.deployed.lock().unwrap();
+        if let Some(previous) = deployed.insert(target.to_string(), sha.to_string()) {
+            let _ = self.deployed_commit.remove_label_values(&[target, &previous]);
+        }
+        self.deployed_commit.with_label_values(&[target, sha]).set(1);
+    }
+
+    /// Records a finished job's outcome and durations.
+    pub fn finished(&self, job: &Job) {
+        let target = job.target.as_str();
+        let status = job.state.as_str();
+        self.deploys.with_label_values(&[target, status]).inc();
+        if let Some(duration_ms) = job.duration_ms {
+            self.deploy_duration
+                .with_label_values(&[target, status])
+                .observe(duration_ms as f64 / 1000.0);
+        }
+        for step in job.steps.iter().filter(|step| step.status != StepStatus::Skipped) {
+            self.step_duration
+                .with_label_values(&[target, &step.name, step.status.as_str()])
+                .observe(step.duration_ms as f64 / 1000.0);
+        }
+
+        if job.state == JobState::Succeeded
+            && let Some(finished_at) = job.finished_at
+        {
+            self.last_success
+                .with_label_values(&[target])
+                .set(finished_at.timestamp_millis() as f64 / 1000.0);
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("text encoding into memory does not fail");
+        String::from_utf8(buffer).expect("the text format is UTF-8")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::jobs::{Phase, StepResult};
+    use crate::testing;
+
+    fn succeeded() -> Job {
+        let mut job = testing::job(JobState::Succeeded);
+        job.finished_at = Some(Utc::now());
+        job.duration_ms = Some(1500);
+        job.steps = vec![
+            StepResult {
+                name: "install".to_string(),
+                phase: Phase::Deploy,
+                status: StepStatus::Succeeded,
+                exit_code: Some(0),
+                duration_ms: 1200,
+                message: None,
+            },
+            StepResult {
+                name: "migrate".to_string(),
+                phase: Phase::Deploy,
+                status: StepStatus::Skipped,
+                exit_code: None,
+                duration_ms: 0,
+                message: None,
+            },
+        ];
+        job
+    }
+
+    #[test]
+    fn exposes_every_family() {
+        let metrics = Metrics::new();
+        metrics.trigger("web", TriggerSource::Push, "started");
+        metrics.queue_depth("web", 1);
+        metrics.rate_limited("web", "client");
+        metrics.rate_limit_buckets("client", 2);
+        metrics.deployed("web", "1111111111111111111111111111111111111111");
+        metrics.finished(&succeeded());
+        let text = metrics.render();
+
+        for (family, kind) in [
+            ("pull_server_triggers_total", "counter"),
+            ("pull_server_deploys_total", "counter"),
+            ("pull_server_deploy_duration_seconds", "histogram"),
+            ("pull_server_step_duration_seconds", "histogram"),
+            ("pull_server_deployed_commit", "gauge"),
+            ("pull_server_queue_depth", "gauge"),
+            ("pull_server_last_success_timestamp_seconds", "gauge"),
+            ("pull_server_rate_limited_total", "counter"),
+            ("pull_server_rate_limit_buckets", "gauge"),
+        ] {
+            assert!(text.contains(&format!("# TYPE {} {}\n", family, kind)), "{} missing from\n{}", family, text);
+        }
+        for line in [
+            r#"pull_server_triggers_total{result="started",source="push",target="web"} 1"#,
+            r#"pull_server_deploys_total{status="succeeded",target="web"} 1"#,
+            r#"pull_server_deploy_duration_seconds_sum{status="succeeded",target="web"} 1.5"#,
+            r#"pull_server_step_duration_seconds_count{status="succeeded",step="install",target="web"} 1"#,
+            r#"pull_server_queue_depth{target="web"} 1"#,
+            r#"pull_server_rate_limited_total{scope="client",target="web"} 1"#,
+            r#"pull_server_rate_limit_buckets{scope="client"} 2"#,
+        ] {
+            assert!(text.contains(line), "{} missing from\n{}", line, text);
+        }
+        assert!(!text.contains(r#"step="migrate""#), "{}", text);
+    }
+
+    #[test]
+    fn labels_only_the_live_commit() {
+        let metrics = Metrics::new();
+        metrics.deployed("web", "1111111111111111111111111111111111111111");
+        metrics.deployed("web", "2222222222222222222222222222222222222222");
+        metrics.deployed("api", "1111111111111111111111111111111111111111");
+        let text = metrics.render();
+        let live: Vec<&str> = text.lines().filter(|line| line.starts_with("pull_server_deployed_commit{")).collect();
+        assert_eq!(
+            live,
+            [
+                r#"pull_server_deployed_commit{sha="1111111111111111111111111111111111111111",target="api"} 1"#,
+                r#"pull_server_deployed_commit{sha="2222222222222222222222222222222222222222",target="web"} 1"#,
+            ]
+        );
+    }
+}
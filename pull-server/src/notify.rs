@@ -0,0 +1,375 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::config::{NotifyEvent, Sink, SinkKind};
+use crate::git::short;
+use crate::jobs::{CommitSummary, Job, JobState, StepStatus, Trigger, TriggerSource};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Wait before the first retry, doubled after every further failure up to [`MAX_BACKOFF`].
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Commits listed in chat messages; the JSON notice carries all the job report has.
+const MAX_LISTED_COMMITS: usize = 10;
+/// Tail of the failing command's output included in notices.
+const MAX_OUTPUT_LINES: usize = 20;
+const MAX_OUTPUT_CHARS: usize = 1000;
+/// Discord rejects messages longer than this.
+const DISCORD_MAX_CHARS: usize = 2000;
+
+/// Tells the configured webhooks about deploys starting and finishing.
+pub struct Notifier {
+    sinks: Vec<Arc<Sink>>,
+    client: reqwest::Client,
+}
+
+/// What a notification says about a job. Sent as is to `json` sinks.
+#[derive(Serialize)]
+struct Notice<'a> {
+    event: NotifyEvent,
+    job_id: Uuid,
+    target: &'a str,
+    branch: &'a str,
+    trigger: &'a Trigger,
+    commit: &'a str,
+    previous_sha: Option<&'a str>,
+    new_sha: Option<&'a str>,
+    commit_count: Option<u64>,
+    commits: &'a [CommitSummary],
+    duration_ms: Option<u64>,
+    /// Why the job failed, was cancelled or was rolled back.
+    message: Option<&'a str>,
+    failed_step: Option<FailedStep>,
+}
+
+#[derive(Serialize)]
+struct FailedStep {
+    name: String,
+    /// The last lines the failing command printed.
+    output: String,
+}
+
+impl Notifier {
+    pub fn new(sinks: Vec<Arc<Sink>>) -> reqwest::Result<Self> {
+        let client = reqwest::Client::builder().timeout(TIMEOUT).build()?;
+        Ok(Notifier { sinks, client })
+    }
+
+    /// Sends `job`'s current state to every sink subscribed to it. Delivery happens in the
+    /// background, so a slow webhook never holds up a deploy.
+    pub fn notify(&self, job: &Job) {
+        let event = match job.state {
+            JobState::Queued => return,
+            JobState::Running => NotifyEvent::Started,
+            JobState::Succeeded => NotifyEvent::Succeeded,
+            JobState::Failed => NotifyEvent::Failed,
+            JobState::Cancelled => NotifyEvent::Cancelled,
+            JobState::RolledBack => NotifyEvent::RolledBack,
+        };
+        let notice = Notice::new(event, job);
+        for sink in self.sinks.iter().filter(|sink| sink.wants(&job.target, event)) {
+            let body = match sink.kind {
+                SinkKind::Slack => json!({ "text": notice.text(|text| format!("*{}*", text), usize::MAX) }),
+                SinkKind::Discord => {
+                    json!({ "content": notice.text(|text| format!("**{}**", text), DISCORD_MAX_CHARS) })
+                }
+                SinkKind::Json => serde_json::to_value(&notice).expect("notices serialize"),
+            };
+            actix_web::rt::spawn(deliver(self.client.clone(), sink.clone(), job.id, body));
+        }
+    }
+}
+
+/// Posts `body` to `sink`, retrying network errors, 429s and 5xx responses.
+async fn deliver(client: reqwest::Client, sink: Arc<Sink>, job_id: Uuid, body: Value) {
+    let host = reqwest::Url::parse(&sink.url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let problem = match client.post(&sink.url).json(&body).send().await {
+            Ok(response) if response.status().is_success() => return,
+            Ok(response) => {
+                let status = response.status();
+                if !status.is_server_error() && status != reqwest::StatusCode::TOO_MANY_REQUESTS {
+                    tracing::error!(
+                        job_id = %job_id,
+                        sink = sink.kind.name(),
+                        host = %host,
+                        "Notification rejected with {}",
+                        status
+                    );
+                    return;
+                }
+                format!("HTTP {}", status)
+            }
+            // Webhook URLs embed their token, so it is kept out of the logs.
+            Err(err) => err.without_url().to_string(),
+        };
+        if attempt > sink.retries {
+            tracing::error!(
+                job_id = %job_id,
+                sink = sink.kind.name(),
+                host = %host,
+                attempts = attempt,
+                "Notification failed: {}",
+                problem
+            );
+            return;
+        }
+        tracing::warn!(
+            job_id = %job_id,
+            sink = sink.kind.name(),
+            host = %host,
+            "Notification failed, retrying in {}s: {}",
+            backoff.as_secs(),
+            problem
+        );
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+impl<'a> Notice<'a> {
+    fn new(event: NotifyEvent, job: &'a Job) -> Self {
+        Notice {
+            event,
+            job_id: job.id,
+            target: &job.target,
+            branch: &job.branch,
+            trigger: &job.trigger,
+            commit: &job.commit,
+            previous_sha: job.previous_sha.as_deref(),
+            new_sha: job.new_sha.as_deref(),
+            commit_count: job.commit_count,
+            commits: &job.commits,
+            duration_ms: job.duration_ms,
+            message: job
+                .message
+                .as_deref()
+                .or(job.error.as_ref().map(|error| error.message.as_str())),
+            failed_step: failed_step(job),
+        }
+    }
+
+    /// The chat message: a headline set in bold, then details, then the failing output.
+    /// Commits are dropped from the end if the message would exceed `max_chars`.
+    fn text(&self, bold: impl Fn(&str) -> String, max_chars: usize) -> String {
+        let mut text = bold(&self.headline());
+        let requester = self.trigger.requester.as_deref().unwrap_or("unknown");
+        text.push_str(&format!(
+            "\n{} by {}, job {}",
+            match self.trigger.source {
+                TriggerSource::Push => "Pushed",
+                TriggerSource::WorkflowRun => "Passed CI, pushed",
+                TriggerSource::Release => "Released",
+                TriggerSource::Rollback => "Rollback requested",
+            },
+            requester,
+            self.job_id
+        ));
+        if let Some(message) = self.message {
+            text.push_str(&format!("\n{}", message));
+        }
+        let failure = self
+            .failed_step
+            .as_ref()
+            .map(|step| format!("\nOutput of {}:\n```\n{}\n```", step.name, step.output))
+            .unwrap_or_default();
+
+        let mut commits = String::new();
+        if self.event != NotifyEvent::Started && !self.commits.is_empty() {
+            let count = self.commit_count.unwrap_or(self.commits.len() as u64);
+            commits.push_str(&format!("\n{} commit{}:", count, if count == 1 { "" } else { "s" }));
+            let mut listed = 0;
+            for commit in self.commits.iter().take(MAX_LISTED_COMMITS) {
+                let line = format!("\n- {} {} ({})", short(&commit.sha), commit.subject, commit.author);
+                if text.chars().count() + commits.chars().count() + line.chars().count() + failure.chars().count()
+                    > max_chars
+                {
+                    break;
+                }
+                commits.push_str(&line);
+                listed += 1;
+            }
+            if (listed as u64) < count {
+                commits.push_str(&format!("\n- and {} more", count - listed as u64));
+            }
+        }
+        text.push_str(&commits);
+        text.push_str(&failure);
+        text.chars().take(max_chars).collect()
+    }
+
+    fn headline(&self) -> String {
+        let duration = self
+            .duration_ms
+            .map(|ms| format!(" in {:.1}s", ms as f64 / 1000.0))
+            .unwrap_or_default();
+        match self.event {
+            NotifyEvent::Started => format!("Deploying {} to {} ({})", short(self.commit), self.target, self.branch),
+            NotifyEvent::Succeeded => match (self.previous_sha, self.new_sha) {
+                (Some(previous), Some(new)) if previous != new => {
+                    format!("Deployed {}: {}..{}{}", self.target, short(previous), short(new), duration)
+                }
+                _ => format!(
+                    "Deployed {}: {} was already live{}",
+                    self.target,
+                    short(self.new_sha.unwrap_or(self.commit)),
+                    duration
+                ),
+            },
+            NotifyEvent::Failed => format!("Deploy of {} to {} failed{}", short(self.commit), self.target, duration),
+            NotifyEvent::Cancelled => format!("Deploy of {} to {} cancelled", short(self.commit), self.target),
+            NotifyEvent::RolledBack => format!(
+                "Deploy of {} to {} rolled back to {}{}",
+                short(self.commit),
+                self.target,
+                self.previous_sha.map(short).unwrap_or("the previous commit"),
+                duration
+            ),
+        }
+    }
+}
+
+/// The failed step and the tail of its last failing command's output.
+fn failed_step(job: &Job) -> Option<FailedStep> {
+    let step = job.steps.iter().rev().find(|step| step.status == StepStatus::Failed)?;
+    let output = job
+        .commands
+        .iter()
+        .rev()
+        .find(|command| command.exit_code != Some(0))
+        .map(|command| if command.stderr.trim().is_empty() { &command.stdout } else { &command.stderr })
+        .map(|output| tail(output))
+        .unwrap_or_default();
+    Some(FailedStep {
+        name: step.name.clone(),
+        output,
+    })
+}
+
+fn tail(output: &str) -> String {
+    let lines: Vec<&str> = output.trim_end().lines().collect();
+    let tail = lines[lines.len().saturating_sub(MAX_OUTPUT_LINES)..].join("\n");
+    let skip = tail.chars().count().saturating_sub(MAX_OUTPUT_CHARS);
+    tail.chars().skip(skip).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::testing::{self, MockServer};
+
+    fn sink(kind: SinkKind, url: &str, retries: u32) -> Arc<Sink> {
+        Arc::new(Sink {
+            kind,
+            url: url.to_string(),
+            targets: Vec::new(),
+            events: vec![NotifyEvent::Succeeded],
+            retries,
+        })
+    }
+
+    fn deployed() -> Job {
+        let mut job = testing::job(JobState::Succeeded);
+        job.previous_sha = Some("1111111111111111111111111111111111111111".to_string());
+        job.new_sha = Some("2222222222222222222222222222222222222222".to_string());
+        job.commit_count = Some(1);
+        job.commits = vec![CommitSummary {
+            sha: "2222222222222222222222222222222222222222".to_string(),
+            author: "Mona".to_string(),
+            subject: "Fix the header".to_string(),
+        }];
+        job.duration_ms = Some(1500);
+        job
+    }
+
+    #[actix_web::test]
+    async fn posts_each_sink_its_body() {
+        let server = MockServer::with_statuses(&[]);
+        let sinks = [SinkKind::Slack, SinkKind::Discord, SinkKind::Json]
+            .into_iter()
+            .map(|kind| sink(kind, &format!("{}/{}", server.url, kind.name()), 0))
+            .collect();
+        let job = deployed();
+        Notifier::new(sinks).unwrap().notify(&job);
+
+        let mut bodies = HashMap::new();
+        for _ in 0..3 {
+            let request = server.next().await;
+            assert_eq!(request.method, "POST");
+            assert_eq!(request.headers["content-type"], "application/json");
+            bodies.insert(request.path, request.body);
+        }
+        let slack = bodies["/slack"]["text"].as_str().unwrap();
+        assert!(slack.starts_with("*Deployed web: 1111111..2222222 in 1.5s*\nPushed by octocat"), "{}", slack);
+        assert!(slack.contains("\n- 2222222 Fix the header (Mona)"), "{}", slack);
+        let discord = bodies["/discord"]["content"].as_str().unwrap();
+        assert!(discord.starts_with("**Deployed web: 1111111..2222222 in 1.5s**"), "{}", discord);
+        let notice = &bodies["/json"];
+        assert_eq!(notice["event"], "succeeded");
+        assert_eq!(notice["job_id"], job.id.to_string());
+        assert_eq!(notice["new_sha"], "2222222222222222222222222222222222222222");
+        assert_eq!(notice["commits"][0]["subject"], "Fix the header");
+    }
+
+    #[actix_web::test]
+    async fn skips_sinks_not_subscribed() {
+        let server = MockServer::with_statuses(&[]);
+        Notifier::new(vec![sink(SinkKind::Json, &server.url, 0)])
+            .unwrap()
+            .notify(&testing::job(JobState::Failed));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(server.received().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn retries_server_errors_and_429() {
+        let server = MockServer::with_statuses(&[503, 429]);
+        let sink = sink(SinkKind::Json, &server.url, 3);
+        deliver(reqwest::Client::new(), sink, Uuid::new_v4(), json!({})).await;
+        assert_eq!(server.received().len(), 3);
+    }
+
+    #[actix_web::test]
+    async fn gives_up_after_the_retries() {
+        let server = MockServer::with_statuses(&[500, 500, 500]);
+        let sink = sink(SinkKind::Json, &server.url, 1);
+        deliver(reqwest::Client::new(), sink, Uuid::new_v4(), json!({})).await;
+        assert_eq!(server.received().len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn does_not_retry_client_errors() {
+        let server = MockServer::with_statuses(&[404]);
+        let sink = sink(SinkKind::Json, &server.url, 3);
+        deliver(reqwest::Client::new(), sink, Uuid::new_v4(), json!({})).await;
+        assert_eq!(server.received().len(), 1);
+    }
+
+    #[test]
+    fn keeps_discord_messages_short() {
+        let mut job = deployed();
+        job.commit_count = Some(500);
+        job.commits = (0..10)
+            .map(|n| CommitSummary {
+                sha: format!("{:040}", n),
+                author: "Mona".to_string(),
+                subject: "x".repeat(300),
+            })
+            .collect();
+        let text = Notice::new(NotifyEvent::Succeeded, &job).text(|text| text.to_string(), DISCORD_MAX_CHARS);
+        assert!(text.chars().count() <= DISCORD_MAX_CHARS);
+        assert!(text.ends_with(" more"), "{}", text);
+    }
+}
@@ -0,0 +1,118 @@
+use std::path::Path;
+use std::time::Instant;
+use tokio::process::Command;
+
+use crate::config::Step;
+use crate::error::Error;
+use crate::jobs::{JobHandle, Phase, StepResult, StepStatus};
+use crate::process;
+
+/// Runs the post-deploy steps in `repo` in order, stopping at the first one that fails.
+///
+/// `changed` lists the files the deploy touched; steps with `paths` are skipped unless one of
+/// them matches. `None` means the change set is unknown, in which case every step runs.
+pub async fn run(
+    steps: &[Step],
+    repo: &Path,
+    changed: Option<&[String]>,
+    phase: Phase,
+    job: &JobHandle,
+) -> Result<(), Error> {
+    for step in steps {
+        if let (Some(filter), Some(changed)) = (&step.paths, changed)
+            && !filter.matches_any(changed)
+        {
+            job.record_step(StepResult {
+                name: step.name.clone(),
+                phase,
+                status: StepStatus::Skipped,
+                exit_code: None,
+                duration_ms: 0,
+                message: Some(format!("No changed files match {}", filter.patterns.join(", "))),
+            });
+            continue;
+        }
+
+        job.record_step_start(&step.name, phase);
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(&step.command).envs(&step.env);
+        match &step.dir {
+            Some(dir) => command.current_dir(repo.join(dir)),
+            None => command.current_dir(repo),
+        };
+
+        let started = Instant::now();
+        let result = process::run(job, command, step.timeout, job.cancellation())
+            .await
+            .and_then(|output| Error::check(&step.command, output));
+        let duration_ms = started.elapsed().as_millis() as u64;
+        match &result {
+            Ok(_) => tracing::info!(job_id = %job.id(), step = %step.name, duration_ms, "Step succeeded"),
+            Err(err) => tracing::warn!(job_id = %job.id(), step = %step.name, duration_ms, "Step failed: {}", err),
+        }
+
+        let exit_code = match &result {
+            Ok(output) => output.status.code(),
+            Err(Error::Exit { exit_code, .. }) => *exit_code,
+            Err(_) => None,
+        };
+        job.record_step(StepResult {
+            name: step.name.clone(),
+            phase,
+            status: if result.is_ok() { StepStatus::Succeeded } else { StepStatus::Failed },
+            exit_code,
+            duration_ms,
+            message: result.as_ref().err().map(ToString::to_string),
+        });
+        if let Err(err) = result {
+            return Err(Error::Step {
+                step: step.name.clone(),
+                source: Box::new(err),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::jobs::{Jobs, Trigger, TriggerSource};
+    use crate::testing::{self, TempDir};
+
+    use super::*;
+
+    fn step(name: &str, paths: &[&str]) -> Step {
+        toml::from_str(&format!("name = {:?}\ncommand = \"true\"\npaths = {:?}", name, paths)).unwrap()
+    }
+
+    /// Runs `steps` for changed files `changed` and returns each step's status.
+    async fn statuses(steps: &[Step], changed: Option<&[String]>) -> Vec<StepStatus> {
+        let dir = TempDir::new();
+        let target = testing::target(dir.path());
+        let trigger = Trigger {
+            source: TriggerSource::Push,
+            requester: None,
+            remote_addr: None,
+        };
+        let job = Jobs::default().create(&target, "a".repeat(40), trigger);
+        job.start();
+        run(steps, dir.path(), changed, Phase::Deploy, &job).await.unwrap();
+        job.snapshot().steps.iter().map(|step| step.status).collect()
+    }
+
+    #[actix_web::test]
+    async fn skips_steps_whose_paths_did_not_change() {
+        let steps = [step("install", &["package.json"]), step("build", &["src/**"])];
+        let changed = ["src/app/main.ts".to_string()];
+        assert_eq!(
+            statuses(&steps, Some(&changed)).await,
+            [StepStatus::Skipped, StepStatus::Succeeded]
+        );
+    }
+
+    #[actix_web::test]
+    async fn runs_every_step_without_a_list_of_changed_files() {
+        let steps = [step("install", &["package.json"]), step("build", &["src/**"])];
+        assert_eq!(statuses(&steps, None).await, [StepStatus::Succeeded, StepStatus::Succeeded]);
+    }
+}
@@ -0,0 +1,143 @@
+use std::io;
+use std::process::{Output, Stdio};
+use std::time::Duration;
+use chrono::Utc;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+use tokio_util::sync::CancellationToken;
+
+use crate::error::Error;
+use crate::events::OutputStream;
+use crate::jobs::{CommandRecord, JobHandle};
+
+/// Runs `command` like [`output`] on behalf of `job`, recorded in its command log and killed
+/// when `cancel` fires, which is usually the job's own cancellation.
+/// Only failures to run the process at all are errors; the exit status is left to the caller.
+pub async fn run(
+    job: &JobHandle,
+    command: Command,
+    timeout: Duration,
+    cancel: &CancellationToken,
+) -> Result<Output, Error> {
+    let description = describe(&command);
+    let started_at = Utc::now();
+    let result = output(command, timeout, cancel, &mut |stream, line| job.record_output(stream, line)).await;
+
+    let (exit_code, stdout, stderr) = match &result {
+        Ok(output) => (
+            output.status.code(),
+            String::from_utf8_lossy(&output.stdout).into_owned(),
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ),
+        Err(err) => (None, String::new(), err.to_string()),
+    };
+    tracing::debug!(job_id = %job.id(), exit_code, "Ran {}", description);
+    job.record(CommandRecord {
+        command: description.clone(),
+        exit_code,
+        stdout,
+        stderr,
+        started_at,
+        finished_at: Utc::now(),
+    });
+    result.map_err(|err| Error::from_io(&description, err, timeout))
+}
+
+fn describe(command: &Command) -> String {
+    let command = command.as_std();
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|part| part.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Runs `command` to completion in its own process group, collecting stdout and stderr and
+/// passing each line to `on_line` as it arrives.
+///
+/// If it has not exited within `timeout`, the whole process group is killed (so helpers such as
+/// ssh or credential prompts spawned by git go with it) and an `ErrorKind::TimedOut` error is
+/// returned. Cancelling `cancel` kills the group the same way and yields `ErrorKind::Interrupted`.
+pub async fn output(
+    mut command: Command,
+    timeout: Duration,
+    cancel: &CancellationToken,
+    on_line: &mut (dyn FnMut(OutputStream, String) + Send),
+) -> io::Result<Output> {
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .kill_on_drop(true);
+
+    let mut child = command.spawn()?;
+    let pid = child.id();
+    let kill_group = || {
+        if let Some(pid) = pid {
+            // SAFETY: killpg only sends a signal; the group was created by process_group(0) above.
+            unsafe { libc::killpg(pid as libc::pid_t, libc::SIGKILL) };
+        }
+    };
+
+    let mut stdout = BufReader::new(child.stdout.take().expect("stdout is piped"));
+    let mut stderr = BufReader::new(child.stderr.take().expect("stderr is piped"));
+    let collect = async {
+        let mut output = (Vec::new(), Vec::new());
+        let mut lines = (Vec::new(), Vec::new());
+        let mut open = (true, true);
+        while open.0 || open.1 {
+            // read_until keeps partial lines in its buffer, so losing the race to the other
+            // stream loses nothing.
+            tokio::select! {
+                read = stdout.read_until(b'\n', &mut lines.0), if open.0 => {
+                    open.0 = read? > 0;
+                    flush_line(&mut lines.0, &mut output.0, OutputStream::Stdout, on_line);
+                }
+                read = stderr.read_until(b'\n', &mut lines.1), if open.1 => {
+                    open.1 = read? > 0;
+                    flush_line(&mut lines.1, &mut output.1, OutputStream::Stderr, on_line);
+                }
+            }
+        }
+        let status = child.wait().await?;
+        Ok(Output {
+            status,
+            stdout: output.0,
+            stderr: output.1,
+        })
+    };
+
+    tokio::select! {
+        result = tokio::time::timeout(timeout, collect) => match result {
+            Ok(result) => result,
+            Err(_) => {
+                kill_group();
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("timed out after {}s", timeout.as_secs()),
+                ))
+            }
+        },
+        _ = cancel.cancelled() => {
+            kill_group();
+            Err(io::Error::new(io::ErrorKind::Interrupted, "cancelled"))
+        }
+    }
+}
+
+/// Moves a line read by `read_until` into the collected output and reports it without its
+/// terminator. The last line of a stream may lack one.
+fn flush_line(
+    line: &mut Vec<u8>,
+    output: &mut Vec<u8>,
+    stream: OutputStream,
+    on_line: &mut (dyn FnMut(OutputStream, String) + Send),
+) {
+    if line.is_empty() {
+        return;
+    }
+    let text = String::from_utf8_lossy(line);
+    on_line(stream, text.trim_end_matches(['\n', '\r']).to_string());
+    output.append(line);
+}
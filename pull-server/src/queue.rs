@@ -0,0 +1,276 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::Target;
+use crate::deploy;
+use crate::git;
+use crate::github::GitHub;
+use crate::history::History;
+use crate::jobs::{JobHandle, Jobs, Trigger};
+use crate::metrics::Metrics;
+use crate::notify::Notifier;
+
+/// Deploy queues by target name. Targets have separate queues, so they deploy in parallel.
+pub type Queues = BTreeMap<String, Arc<DeployQueue>>;
+
+/// Serializes deploys of one target so that two git processes never share a working tree.
+///
+/// At most one job runs at a time. Triggers that arrive meanwhile collapse into a single pending
+/// job, which is retargeted to the newest commit and runs once the current one finishes. With a
+/// cooldown, a job also waits until that long after the previous deploy finished, and triggers
+/// arriving meanwhile are merged into it the same way.
+pub struct DeployQueue {
+    target: Arc<Target>,
+    history: Arc<History>,
+    metrics: Arc<Metrics>,
+    notifier: Arc<Notifier>,
+    /// Used when the target sets `github_repository`.
+    github: Option<Arc<GitHub>>,
+    slots: Mutex<Slots>,
+    /// When the last deploy finished, for the target's cooldown.
+    last_finished: Mutex<Option<Instant>>,
+}
+
+#[derive(Default)]
+struct Slots {
+    running: Option<Arc<JobHandle>>,
+    pending: Option<Arc<JobHandle>>,
+}
+
+/// What happened to a trigger handed to [`DeployQueue::submit`].
+pub enum Submitted {
+    /// Nothing was running; the job started right away.
+    Started(Arc<JobHandle>),
+    /// A deploy is running; the job will run after it.
+    Queued(Arc<JobHandle>),
+    /// A follow-up deploy was already waiting; it now targets this trigger's commit instead.
+    Merged(Arc<JobHandle>),
+}
+
+impl Submitted {
+    pub fn job(&self) -> &Arc<JobHandle> {
+        match self {
+            Submitted::Started(job) | Submitted::Queued(job) | Submitted::Merged(job) => job,
+        }
+    }
+
+    pub fn status(&self) -> &'static str {
+        match self {
+            Submitted::Started(_) => "started",
+            Submitted::Queued(_) => "queued",
+            Submitted::Merged(_) => "merged",
+        }
+    }
+}
+
+impl DeployQueue {
+    pub fn new(
+        target: Arc<Target>,
+        history: Arc<History>,
+        metrics: Arc<Metrics>,
+        notifier: Arc<Notifier>,
+        github: Option<Arc<GitHub>>,
+    ) -> Self {
+        DeployQueue {
+            target,
+            history,
+            metrics,
+            notifier,
+            github,
+            slots: Mutex::new(Slots::default()),
+            last_finished: Mutex::new(None),
+        }
+    }
+
+    pub fn target(&self) -> &Arc<Target> {
+        &self.target
+    }
+
+    /// Time left until the cooldown after the last deploy is over.
+    fn cooldown_left(&self) -> Duration {
+        match *self.last_finished.lock().unwrap() {
+            Some(finished) => self.target.cooldown.saturating_sub(finished.elapsed()),
+            None => Duration::ZERO,
+        }
+    }
+
+    pub fn submit(self: &Arc<Self>, jobs: &Jobs, commit: String, trigger: Trigger) -> Submitted {
+        let mut slots = self.slots.lock().unwrap();
+
+        if slots.running.is_none() {
+            let job = jobs.create(&self.target, commit, trigger);
+            slots.running = Some(job.clone());
+            self.metrics.queue_depth(&self.target.name, slots.depth());
+            actix_web::rt::spawn(self.clone().work(job.clone()));
+            if self.cooldown_left().is_zero() {
+                return Submitted::Started(job);
+            }
+            return Submitted::Queued(job);
+        }
+
+        // The running slot's job is still queued while it waits out the cooldown.
+        if let Some(waiting) = slots.pending.as_ref().or(slots.running.as_ref())
+            && waiting.retarget(commit.clone(), trigger.clone())
+        {
+            return Submitted::Merged(waiting.clone());
+        }
+
+        let job = jobs.create(&self.target, commit, trigger);
+        slots.pending = Some(job.clone());
+        self.metrics.queue_depth(&self.target.name, slots.depth());
+        Submitted::Queued(job)
+    }
+
+    async fn work(self: Arc<Self>, first: Arc<JobHandle>) {
+        let mut next = Some(first);
+        while let Some(job) = next {
+            let mut deployment = None;
+            let wait = self.cooldown_left();
+            if !wait.is_zero() {
+                tracing::info!(
+                    job_id = %job.id(),
+                    target = %self.target.name,
+                    "Waiting {}s for the cooldown after the last deploy",
+                    wait.as_secs_f64().ceil()
+                );
+                tokio::time::sleep(wait).await;
+            }
+            if job.start() {
+                let started = job.snapshot();
+                self.notifier.notify(&started);
+                if let (Some(github), Some(target)) = (&self.github, &self.target.github) {
+                    deployment = Some(github.start(target, &started));
+                }
+                deploy::run(self.target.clone(), job.clone()).await;
+                *self.last_finished.lock().unwrap() = Some(Instant::now());
+            }
+            let report = job.snapshot();
+            self.notifier.notify(&report);
+            if let Some(deployment) = deployment {
+                deployment.finish(&report);
+            }
+            if let Err(err) = self.history.append(&report) {
+                tracing::error!(job_id = %job.id(), "Failed to record the job in the deploy history: {}", err);
+            }
+            self.metrics.finished(&report);
+            self.refresh_deployed().await;
+
+            let mut slots = self.slots.lock().unwrap();
+            slots.running = slots.pending.take();
+            next = slots.running.clone();
+            self.metrics.queue_depth(&self.target.name, slots.depth());
+        }
+    }
+
+    /// Points the deployed-commit metric at the target's HEAD, wherever the last job left it.
+    pub async fn refresh_deployed(&self) {
+        match git::current_head(&self.target.repo, self.target.git_timeout).await {
+            Ok(Some(head)) => self.metrics.deployed(&self.target.name, &head),
+            Ok(None) => {}
+            Err(err) => tracing::warn!(target = %self.target.name, "Failed to read HEAD: {}", err),
+        }
+    }
+}
+
+impl Slots {
+    fn depth(&self) -> usize {
+        self.running.is_some() as usize + self.pending.is_some() as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::jobs::{JobState, TriggerSource};
+    use crate::testing::{self, TempDir};
+
+    use super::*;
+
+    /// A queue for a target in `dir`. Its worker only runs once the test awaits something, so until
+    /// then a started job stays put.
+    fn queue(dir: &TempDir, cooldown: Duration) -> Arc<DeployQueue> {
+        let mut target = testing::target(dir.path());
+        target.cooldown = cooldown;
+        Arc::new(DeployQueue::new(
+            Arc::new(target),
+            Arc::new(History::open(&dir.path().join("deploys.jsonl")).unwrap()),
+            Arc::new(Metrics::new()),
+            Arc::new(Notifier::new(Vec::new()).unwrap()),
+            None,
+        ))
+    }
+
+    fn trigger(requester: &str) -> Trigger {
+        Trigger {
+            source: TriggerSource::Push,
+            requester: Some(requester.to_string()),
+            remote_addr: None,
+        }
+    }
+
+    #[actix_web::test]
+    async fn coalesces_triggers_behind_a_running_deploy() {
+        let dir = TempDir::new();
+        let queue = queue(&dir, Duration::ZERO);
+        let jobs = Jobs::default();
+
+        let first = queue.submit(&jobs, "a".repeat(40), trigger("alice"));
+        assert_eq!(first.status(), "started");
+        // What the worker would do first, had it run yet.
+        assert!(first.job().start());
+
+        let second = queue.submit(&jobs, "b".repeat(40), trigger("bob"));
+        assert_eq!(second.status(), "queued");
+        assert_ne!(second.job().id(), first.job().id());
+        for (commit, requester) in [("c", "carol"), ("d", "dave")] {
+            let merged = queue.submit(&jobs, commit.repeat(40), trigger(requester));
+            assert_eq!(merged.status(), "merged");
+            assert_eq!(merged.job().id(), second.job().id());
+        }
+
+        assert_eq!(first.job().commit(), "a".repeat(40));
+        assert_eq!(second.job().commit(), "d".repeat(40));
+        assert_eq!(second.job().trigger().requester.as_deref(), Some("dave"));
+        assert_eq!(second.job().state(), JobState::Queued);
+        let slots = queue.slots.lock().unwrap();
+        assert_eq!(slots.depth(), 2);
+    }
+
+    #[actix_web::test]
+    async fn queues_a_new_follow_up_once_the_last_one_started() {
+        let dir = TempDir::new();
+        let queue = queue(&dir, Duration::ZERO);
+        let jobs = Jobs::default();
+        let first = queue.submit(&jobs, "a".repeat(40), trigger("alice"));
+        assert!(first.job().start());
+        let second = queue.submit(&jobs, "b".repeat(40), trigger("bob"));
+
+        // The follow-up moves into the running slot and starts, like the worker does.
+        {
+            let mut slots = queue.slots.lock().unwrap();
+            slots.running = slots.pending.take();
+        }
+        assert!(second.job().start());
+        let third = queue.submit(&jobs, "c".repeat(40), trigger("carol"));
+        assert_eq!(third.status(), "queued");
+        assert_ne!(third.job().id(), second.job().id());
+        assert_eq!(second.job().commit(), "b".repeat(40));
+    }
+
+    #[actix_web::test]
+    async fn merges_triggers_into_a_job_waiting_out_the_cooldown() {
+        let dir = TempDir::new();
+        let queue = queue(&dir, Duration::from_secs(60));
+        *queue.last_finished.lock().unwrap() = Some(Instant::now());
+        let jobs = Jobs::default();
+
+        let first = queue.submit(&jobs, "a".repeat(40), trigger("alice"));
+        assert_eq!(first.status(), "queued");
+        assert!(queue.cooldown_left() > Duration::from_secs(59));
+        let second = queue.submit(&jobs, "b".repeat(40), trigger("bob"));
+        assert_eq!(second.status(), "merged");
+        assert_eq!(second.job().id(), first.job().id());
+        assert_eq!(first.job().commit(), "b".repeat(40));
+        assert!(queue.slots.lock().unwrap().pending.is_none());
+    }
+}
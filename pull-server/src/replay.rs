@@ -0,0 +1,318 @@
+use std::collections::{HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+
+use crate::config::ReplayConfig;
+use crate::error::Error;
+
+/// Longest nonce a rollback or cancel request may carry; plenty for a UUID or a random token.
+const MAX_NONCE_CHARS: usize = 128;
+/// Longest ID stored, leaving room for the action and target a nonce is keyed by.
+const MAX_ID_CHARS: usize = 512;
+
+/// Rejects webhooks and rollbacks that were delivered before or were sent outside the configured
+/// window.
+///
+/// The newest `capacity` delivery IDs are kept in memory and in a file with one ID per line, so a
+/// replay is still caught after a restart. The file is rewritten once it holds twice that many.
+/// An ID is only remembered once its request was accepted, so a delivery refused for another
+/// reason can be redelivered.
+pub struct ReplayGuard {
+    path: PathBuf,
+    capacity: usize,
+    max_age: Option<chrono::Duration>,
+    seen: Mutex<Seen>,
+}
+
+struct Seen {
+    order: VecDeque<String>,
+    ids: HashSet<String>,
+    /// IDs of requests being handled, so a copy arriving meanwhile is refused too.
+    claimed: HashSet<String>,
+    file: File,
+    /// Lines in the file, which grows past `order` until it is compacted.
+    lines: usize,
+}
+
+impl ReplayGuard {
+    pub fn open(config: &ReplayConfig) -> io::Result<Self> {
+        let contents = match fs::read_to_string(&config.store) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+        let lines: Vec<&str> = contents.lines().filter(|line| !line.is_empty()).collect();
+        let order: VecDeque<String> = lines[lines.len().saturating_sub(config.capacity)..]
+            .iter()
+            .map(|id| id.to_string())
+            .collect();
+        let file = rewrite(&config.store, &order)?;
+        Ok(ReplayGuard {
+            path: config.store.clone(),
+            capacity: config.capacity,
+            max_age: config
+                .max_age
+                .map(|max_age| chrono::Duration::from_std(max_age).unwrap_or(chrono::Duration::MAX)),
+            seen: Mutex::new(Seen {
+                ids: order.iter().cloned().collect(),
+                claimed: HashSet::new(),
+                lines: order.len(),
+                order,
+                file,
+            }),
+        })
+    }
+
+    /// Checks a verified request's delivery ID and send time, either of which it may lack. The
+    /// ID is held until the returned claim is remembered or dropped.
+    pub fn check(&self, delivery: Option<&str>, sent_at: Option<DateTime<Utc>>) -> Result<Claim<'_>, Error> {
+        if let (Some(max_age), Some(sent_at)) = (self.max_age, sent_at) {
+            let age = Utc::now() - sent_at;
+            // A time far ahead would outlive its ID in the store.
+            if age > max_age || -age > max_age {
+                return Err(Error::StalePayload(format!(
+                    "sent at {}, more than {}s {}",
+                    sent_at.to_rfc3339(),
+                    max_age.num_seconds(),
+                    if age > max_age { "ago" } else { "from now" }
+                )));
+            }
+        }
+        let Some(id) = delivery else {
+            return Ok(Claim { guard: self, id: None });
+        };
+        // The store holds one ID per line, so an ID that cannot be written as one is never held.
+        if id.is_empty() || id.chars().count() > MAX_ID_CHARS || id.chars().any(char::is_control) {
+            return Err(Error::InvalidPayload(format!("Invalid delivery ID {:?}", id)));
+        }
+
+        let mut seen = self.seen.lock().unwrap();
+        if seen.ids.contains(id) || !seen.claimed.insert(id.to_string()) {
+            return Err(Error::DuplicateDelivery(id.to_string()));
+        }
+        Ok(Claim {
+            guard: self,
+            id: Some(id.to_string()),
+        })
+    }
+
+    fn remember(&self, id: &str) -> io::Result<()> {
+        let mut seen = self.seen.lock().unwrap();
+        seen.claimed.remove(id);
+        seen.file.write_all(format!("{}\n", id).as_bytes())?;
+        seen.lines += 1;
+        seen.ids.insert(id.to_string());
+        seen.order.push_back(id.to_string());
+        if seen.order.len() > self.capacity
+            && let Some(oldest) = seen.order.pop_front()
+        {
+            seen.ids.remove(&oldest);
+        }
+        if seen.lines >= self.capacity * 2 {
+            seen.file = rewrite(&self.path, &seen.order)?;
+            seen.lines = seen.order.len();
+        }
+        Ok(())
+    }
+}
+
+/// Refuses a client-chosen nonce that is empty, too long or holds control characters.
+pub fn check_nonce(nonce: &str) -> Result<(), Error> {
+    let problem = if nonce.is_empty() {
+        "must not be empty"
+    } else if nonce.chars().count() > MAX_NONCE_CHARS {
+        "is too long"
+    } else if nonce.chars().any(char::is_control) {
+        "must not contain control characters"
+    } else {
+        return Ok(());
+    };
+    Err(Error::InvalidPayload(format!("nonce {}", problem)))
+}
+
+/// A delivery ID held by [`ReplayGuard::check`]. Dropping it lets the ID be delivered again.
+pub struct Claim<'a> {
+    guard: &'a ReplayGuard,
+    id: Option<String>,
+}
+
+impl Claim<'_> {
+    /// Refuses the ID from now on, once its request was accepted.
+    pub fn remember(mut self) {
+        let Some(id) = self.id.take() else {
+            return;
+        };
+        // The deploy is already queued, so a broken store is no reason to fail the request.
+        if let Err(err) = self.guard.remember(&id) {
+            tracing::error!(delivery = %id, "Failed to record the delivery: {}", err);
+        }
+    }
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = &self.id {
+            self.guard.seen.lock().unwrap().claimed.remove(id);
+        }
+    }
+}
+
+/// Replaces the store with `ids` and returns it opened for appending.
+fn rewrite(path: &Path, ids: &VecDeque<String>) -> io::Result<File> {
+    let temporary = path.with_extension("tmp");
+    let mut contents = String::new();
+    for id in ids {
+        contents.push_str(id);
+        contents.push('\n');
+    }
+    fs::write(&temporary, contents)?;
+    fs::rename(&temporary, path)?;
+    OpenOptions::new().append(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    struct Store(TempDir);
+
+    impl Store {
+        fn new() -> Self {
+            Store(TempDir::new())
+        }
+
+        fn config(&self, capacity: usize) -> ReplayConfig {
+            ReplayConfig {
+                store: self.0.path().join("deliveries.txt"),
+                capacity,
+                max_age: Some(Duration::from_secs(60)),
+            }
+        }
+
+        fn lines(&self) -> Vec<String> {
+            fs::read_to_string(self.0.path().join("deliveries.txt"))
+                .unwrap()
+                .lines()
+                .map(str::to_string)
+                .collect()
+        }
+    }
+
+    fn remember(guard: &ReplayGuard, ids: &[&str]) {
+        for id in ids {
+            guard.check(Some(id), None).unwrap().remember();
+        }
+    }
+
+    fn is_duplicate(guard: &ReplayGuard, id: &str) -> bool {
+        matches!(guard.check(Some(id), None), Err(Error::DuplicateDelivery(_)))
+    }
+
+    #[test]
+    fn refuses_remembered_and_claimed_ids() {
+        let store = Store::new();
+        let guard = ReplayGuard::open(&store.config(10)).unwrap();
+        let claim = guard.check(Some("a"), None).unwrap();
+        assert!(is_duplicate(&guard, "a"));
+        claim.remember();
+        assert!(is_duplicate(&guard, "a"));
+        assert!(!is_duplicate(&guard, "b"));
+        assert!(guard.check(None, None).is_ok());
+        assert!(guard.check(None, None).is_ok());
+    }
+
+    #[test]
+    fn releases_ids_that_are_not_remembered() {
+        let store = Store::new();
+        let guard = ReplayGuard::open(&store.config(10)).unwrap();
+        drop(guard.check(Some("a"), None).unwrap());
+        remember(&guard, &["a"]);
+        assert!(is_duplicate(&guard, "a"));
+    }
+
+    #[test]
+    fn forgets_the_oldest_ids_past_capacity() {
+        let store = Store::new();
+        let guard = ReplayGuard::open(&store.config(2)).unwrap();
+        remember(&guard, &["a", "b", "c"]);
+        assert!(!is_duplicate(&guard, "a"));
+        assert!(is_duplicate(&guard, "b"));
+        assert!(is_duplicate(&guard, "c"));
+    }
+
+    #[test]
+    fn compacts_the_store_at_twice_the_capacity() {
+        let store = Store::new();
+        let guard = ReplayGuard::open(&store.config(2)).unwrap();
+        remember(&guard, &["a", "b", "c"]);
+        assert_eq!(store.lines(), ["a", "b", "c"]);
+        remember(&guard, &["d"]);
+        assert_eq!(store.lines(), ["c", "d"]);
+        remember(&guard, &["e"]);
+        assert_eq!(store.lines(), ["c", "d", "e"]);
+    }
+
+    #[test]
+    fn remembers_ids_across_restarts() {
+        let store = Store::new();
+        remember(&ReplayGuard::open(&store.config(3)).unwrap(), &["a", "b", "c"]);
+
+        let guard = ReplayGuard::open(&store.config(2)).unwrap();
+        assert_eq!(store.lines(), ["b", "c"]);
+        assert!(!is_duplicate(&guard, "a"));
+        assert!(is_duplicate(&guard, "b"));
+        assert!(is_duplicate(&guard, "c"));
+    }
+
+    #[test]
+    fn refuses_payloads_sent_too_long_ago_or_ahead() {
+        let store = Store::new();
+        let guard = ReplayGuard::open(&store.config(10)).unwrap();
+        let stale = |offset: i64| {
+            matches!(
+                guard.check(None, Some(Utc::now() + chrono::Duration::seconds(offset))),
+                Err(Error::StalePayload(_))
+            )
+        };
+        assert!(!stale(-30));
+        assert!(!stale(30));
+        assert!(stale(-120));
+        assert!(stale(120));
+
+        let mut config = store.config(10);
+        config.max_age = None;
+        let guard = ReplayGuard::open(&config).unwrap();
+        assert!(guard.check(None, Some(Utc::now() - chrono::Duration::days(30))).is_ok());
+    }
+
+    #[test]
+    fn refuses_nonces_that_cannot_be_stored() {
+        assert!(check_nonce("0f8e2d4a-2c51-4d7e-9d1b-6c3f5a7e9b20").is_ok());
+        assert!(check_nonce(&"n".repeat(MAX_NONCE_CHARS)).is_ok());
+        for nonce in ["", "a\nb", "a\rb", "a\u{0}b", &"n".repeat(MAX_NONCE_CHARS + 1)] {
+            assert!(matches!(check_nonce(nonce), Err(Error::InvalidPayload(_))), "{:?}", nonce);
+        }
+    }
+
+    #[test]
+    fn never_stores_an_id_across_lines() {
+        let store = Store::new();
+        let guard = ReplayGuard::open(&store.config(10)).unwrap();
+        assert!(matches!(guard.check(Some("rollback/web/a\nb"), None), Err(Error::InvalidPayload(_))));
+        assert!(matches!(guard.check(Some(""), None), Err(Error::InvalidPayload(_))));
+        remember(&guard, &["rollback/web/a"]);
+        drop(guard);
+
+        let guard = ReplayGuard::open(&store.config(10)).unwrap();
+        assert_eq!(store.lines(), ["rollback/web/a"]);
+        assert!(is_duplicate(&guard, "rollback/web/a"));
+        assert!(!is_duplicate(&guard, "b"));
+    }
+}
@@ -0,0 +1,229 @@
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::config::Target;
+use crate::error::Error;
+use crate::git;
+use crate::history::History;
+
+/// Body of `POST /deploy/{target}/rollback`. Only `nonce` and `timestamp` are required, so a
+/// captured request cannot be sent again.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RollbackRequest {
+    /// Unique per request; a nonce already seen is refused like a redelivered webhook.
+    pub nonce: Option<String>,
+    /// When the request was made, RFC 3339; refused once older than `replay.max_age`.
+    pub timestamp: Option<DateTime<Utc>>,
+    /// Redeploy the commit a past deploy left live.
+    pub deploy_id: Option<Uuid>,
+    /// Redeploy this commit, which the history must show was live. May be abbreviated.
+    pub sha: Option<String>,
+    /// Who asked, for the deploy history.
+    pub requester: Option<String>,
+}
+
+/// Picks the commit to roll `target` back to.
+///
+/// Without a deploy ID or SHA this is the last successful deploy before the one that put the
+/// current HEAD live, or the commit the first recorded deploy replaced. Earlier rollbacks are
+/// skipped, so rolling back twice steps back twice. Only commits the history shows were live are
+/// ever picked, so a rollback cannot bring back a commit that failed to deploy.
+pub async fn resolve(target: &Target, history: &History, request: &RollbackRequest) -> Result<String, Error> {
+    match (&request.deploy_id, &request.sha) {
+        (Some(_), Some(_)) => Err(Error::InvalidPayload("give either deploy_id or sha, not both".to_string())),
+        (None, Some(sha)) if !is_sha_prefix(sha) => {
+            Err(Error::InvalidPayload(format!("Invalid commit SHA: {}", sha)))
+        }
+        (None, Some(sha)) => by_sha(&history.entries().await.map_err(Error::History)?, &target.name, sha),
+        (Some(id), None) => by_deploy(&history.entries().await.map_err(Error::History)?, &target.name, *id),
+        (None, None) => {
+            let head = git::current_head(&target.repo, target.git_timeout)
+                .await?
+                .ok_or_else(|| Error::RepoNotFound {
+                    path: target.repo.clone(),
+                })?;
+            before(&history.entries().await.map_err(Error::History)?, &target.name, &head)
+        }
+    }
+}
+
+/// The commit live on `target` whose SHA starts with `prefix`.
+fn by_sha(entries: &[Value], target: &str, prefix: &str) -> Result<String, Error> {
+    let prefix = prefix.to_lowercase();
+    let mut matches: Vec<&str> = entries
+        .iter()
+        .filter(|entry| entry["target"].as_str() == Some(target))
+        .flat_map(|entry| [live_commit(entry), entry["previous_sha"].as_str()])
+        .flatten()
+        .filter(|sha| sha.starts_with(&prefix))
+        .collect();
+    matches.sort_unstable();
+    matches.dedup();
+    match matches[..] {
+        [] => Err(Error::DeployNotFound(format!("{} was never live on {}", prefix, target))),
+        [sha] => Ok(sha.to_string()),
+        _ => Err(Error::InvalidPayload(format!(
+            "{} is ambiguous: {} commits that were live on {} start with it",
+            prefix,
+            matches.len(),
+            target
+        ))),
+    }
+}
+
+/// The commit deploy `id` of `target` left live.
+fn by_deploy(entries: &[Value], target: &str, id: Uuid) -> Result<String, Error> {
+    let id = id.to_string();
+    let entry = entries
+        .iter()
+        .filter(|entry| entry["target"].as_str() == Some(target))
+        .find(|entry| entry["job_id"].as_str() == Some(id.as_str()))
+        .ok_or_else(|| Error::DeployNotFound(format!("no deploy {} of {}", id, target)))?;
+    live_commit(entry).map(str::to_string).ok_or_else(|| {
+        Error::DeployNotFound(format!(
+            "deploy {} of {} {} and left no commit live",
+            id,
+            target,
+            entry["status"].as_str().unwrap_or("did not finish")
+        ))
+    })
+}
+
+/// The commit live on `target` before the deploy that put `head` live, from `entries` newest first.
+fn before(entries: &[Value], target: &str, head: &str) -> Result<String, Error> {
+    let deploys: Vec<&Value> = entries.iter().filter(|entry| is_successful_deploy(entry, target)).collect();
+    // The first recorded deploy replaced a commit that was live before the history began.
+    let first_previous = deploys.last().and_then(|entry| entry["previous_sha"].as_str());
+    // Deploys older than the one that put HEAD live; all of them if HEAD got there another way,
+    // unless it is already back where the history began.
+    let older = match deploys.iter().position(|entry| entry["new_sha"].as_str() == Some(head)) {
+        Some(index) => &deploys[index + 1..],
+        None if first_previous == Some(head) => &[],
+        None => &deploys[..],
+    };
+    older
+        .iter()
+        .filter_map(|entry| entry["new_sha"].as_str())
+        .chain(first_previous)
+        .find(|sha| *sha != head)
+        .map(str::to_string)
+        .ok_or_else(|| Error::DeployNotFound(format!("no successful deploy of {} before {}", target, head)))
+}
+
+/// Whether `value` can name a commit: 4 to 40 hex digits, as git abbreviates them.
+fn is_sha_prefix(value: &str) -> bool {
+    (4..=40).contains(&value.len()) && value.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+/// The commit a finished deploy left live: the new one if it succeeded, the restored one if it was
+/// rolled back, none if it failed or was cancelled.
+fn live_commit(entry: &Value) -> Option<&str> {
+    match entry["status"].as_str()? {
+        "succeeded" => entry["new_sha"].as_str(),
+        "rolled_back" => entry["previous_sha"].as_str(),
+        _ => None,
+    }
+}
+
+fn is_successful_deploy(entry: &Value, target: &str) -> bool {
+    entry["target"].as_str() == Some(target)
+        && entry["status"].as_str() == Some("succeeded")
+        && entry["trigger"]["source"].as_str() != Some("rollback")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const A: &str = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+    const B: &str = "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb";
+    const C: &str = "cccccccccccccccccccccccccccccccccccccccc";
+    const D: &str = "dddddddddddddddddddddddddddddddddddddddd";
+    const JOB: &str = "5b0c4c1e-6f0e-4f57-9b43-3f1d2b7f4a11";
+
+    fn entry(status: &str, source: &str, previous: &str, new: &str) -> Value {
+        json!({
+            "job_id": Uuid::new_v4(),
+            "status": status,
+            "target": "web",
+            "trigger": {"source": source},
+            "previous_sha": previous,
+            "new_sha": new,
+        })
+    }
+
+    fn deploy(previous: &str, new: &str) -> Value {
+        entry("succeeded", "push", previous, new)
+    }
+
+    fn not_found(result: Result<String, Error>) -> bool {
+        matches!(result, Err(Error::DeployNotFound(_)))
+    }
+
+    #[test]
+    fn finds_the_commit_a_deploy_left_live() {
+        let mut succeeded = deploy(A, B);
+        succeeded["job_id"] = Value::from(JOB);
+        let id = JOB.parse().unwrap();
+        assert_eq!(by_deploy(&[succeeded], "web", id).unwrap(), B);
+
+        let mut rolled_back = entry("rolled_back", "push", A, B);
+        rolled_back["job_id"] = Value::from(JOB);
+        assert_eq!(by_deploy(&[rolled_back.clone()], "web", id).unwrap(), A);
+
+        let mut failed = entry("failed", "push", A, B);
+        failed["job_id"] = Value::from(JOB);
+        assert!(not_found(by_deploy(&[failed], "web", id)));
+        assert!(not_found(by_deploy(&[rolled_back.clone()], "api", id)));
+        assert!(not_found(by_deploy(&[rolled_back], "web", Uuid::new_v4())));
+    }
+
+    #[test]
+    fn finds_live_commits_by_sha_or_prefix() {
+        let entries = [entry("failed", "push", B, C), deploy(A, B)];
+        assert_eq!(by_sha(&entries, "web", B).unwrap(), B);
+        assert_eq!(by_sha(&entries, "web", "AAAA").unwrap(), A);
+        assert!(not_found(by_sha(&entries, "web", &C[..7])));
+        assert!(not_found(by_sha(&entries, "api", B)));
+
+        let entries = [deploy(A, "abcd000000000000000000000000000000000000")];
+        assert!(matches!(by_sha(&entries, "web", "a"), Err(Error::InvalidPayload(_))));
+        assert_eq!(by_sha(&entries, "web", "abcd").unwrap(), "abcd000000000000000000000000000000000000");
+        assert!(is_sha_prefix("abcd") && is_sha_prefix(A));
+        assert!(!is_sha_prefix("abc") && !is_sha_prefix("HEAD~1") && !is_sha_prefix(&format!("{}a", A)));
+    }
+
+    #[test]
+    fn steps_back_past_failures_and_other_targets() {
+        let mut other = deploy(B, D);
+        other["target"] = Value::from("api");
+        let entries = [deploy(B, C), entry("failed", "push", A, D), other, deploy(A, B)];
+        assert_eq!(before(&entries, "web", C).unwrap(), B);
+        // A HEAD the history does not know goes back to the newest deploy.
+        assert_eq!(before(&entries, "web", D).unwrap(), C);
+    }
+
+    #[test]
+    fn rolls_back_a_rollback_further() {
+        // C went live, a rollback restored B, and a second rollback should go to A.
+        let entries = [entry("succeeded", "rollback", C, B), deploy(B, C), deploy(A, B)];
+        assert_eq!(before(&entries, "web", B).unwrap(), A);
+    }
+
+    #[test]
+    fn falls_back_to_what_the_first_deploy_replaced() {
+        assert_eq!(before(&[deploy(A, B)], "web", B).unwrap(), A);
+        assert_eq!(before(&[deploy(B, C), deploy(A, B)], "web", B).unwrap(), A);
+        assert!(not_found(before(&[deploy(A, B)], "web", A)));
+        assert!(not_found(before(&[], "web", A)));
+        // A first deploy into an empty checkout replaced nothing.
+        let mut first = deploy(A, B);
+        first["previous_sha"] = Value::Null;
+        assert!(not_found(before(&[first], "web", B)));
+    }
+}
@@ -0,0 +1,198 @@
+//! Helpers for unit tests: an HTTP server standing in for webhooks and APIs, temporary
+//! directories and sample jobs.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::config::{DeployMode, Target};
+use crate::jobs::{DiffStat, Job, JobState, Trigger, TriggerSource};
+use crate::webhook::Provider;
+
+/// How long [`MockServer::next`] waits for a request.
+const WAIT: Duration = Duration::from_secs(10);
+
+/// A request received by a [`MockServer`].
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// Header names are lowercased.
+    pub headers: HashMap<String, String>,
+    /// `Value::Null` if the body is not JSON.
+    pub body: Value,
+}
+
+/// An HTTP/1.1 server on a local port, answering one request per connection.
+pub struct MockServer {
+    pub url: String,
+    requests: mpsc::Receiver<Request>,
+}
+
+impl MockServer {
+    /// Answers each request with the status and JSON body `respond` returns for it.
+    pub fn start(respond: impl Fn(&Request) -> (u16, String) + Send + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, requests) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else {
+                    continue;
+                };
+                let Some(request) = read_request(&mut stream) else {
+                    continue;
+                };
+                let (status, body) = respond(&request);
+                // Handed over before answering, so the request is there once the client returns.
+                let _ = sender.send(request);
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+                     Connection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+            }
+        });
+        MockServer { url, requests }
+    }
+
+    /// Answers with `statuses` in turn, then with 200.
+    pub fn with_statuses(statuses: &[u16]) -> Self {
+        let statuses = Mutex::new(statuses.iter().copied().collect::<VecDeque<u16>>());
+        Self::start(move |_| (statuses.lock().unwrap().pop_front().unwrap_or(200), "{}".to_string()))
+    }
+
+    /// Waits for the next request, without blocking tasks spawned on the test's runtime.
+    pub async fn next(&self) -> Request {
+        let deadline = Instant::now() + WAIT;
+        loop {
+            match self.requests.try_recv() {
+                Ok(request) => return request,
+                Err(TryRecvError::Empty) if Instant::now() < deadline => {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                Err(err) => panic!("No request within {}s: {}", WAIT.as_secs(), err),
+            }
+        }
+    }
+
+    /// Requests received so far and not yet taken.
+    pub fn received(&self) -> Vec<Request> {
+        self.requests.try_iter().collect()
+    }
+}
+
+fn read_request(stream: &mut TcpStream) -> Option<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let path = parts.next()?.to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':')?;
+        headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+    }
+    let length = headers.get("content-length").and_then(|length| length.parse().ok()).unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+    Some(Request {
+        method,
+        path,
+        headers,
+        body: serde_json::from_slice(&body).unwrap_or(Value::Null),
+    })
+}
+
+/// A fresh directory under the system's temporary directory, removed when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        let dir = std::env::temp_dir().join(format!("pull-server-test-{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Target `web` deploying branch `main` of `repo`, without steps, health check or cooldown.
+pub fn target(repo: &Path) -> Target {
+    Target {
+        name: "web".to_string(),
+        repo: repo.to_path_buf(),
+        git_dir: repo.join(".git"),
+        secret: b"s3cret".to_vec(),
+        provider: Provider::Github,
+        branch: "main".to_string(),
+        remote: "origin".to_string(),
+        mode: DeployMode::Reset,
+        git_timeout: Duration::from_secs(10),
+        cooldown: Duration::ZERO,
+        steps: Vec::new(),
+        health: None,
+        workflows: Vec::new(),
+        releases: false,
+        prereleases: false,
+        github: None,
+    }
+}
+
+/// A job of target `web` for a pushed commit, otherwise blank.
+pub fn job(state: JobState) -> Job {
+    Job {
+        id: Uuid::new_v4(),
+        state,
+        outcome: None,
+        target: "web".to_string(),
+        trigger: Trigger {
+            source: TriggerSource::Push,
+            requester: Some("octocat".to_string()),
+            remote_addr: None,
+        },
+        branch: "main".to_string(),
+        commit: "1111111111111111111111111111111111111111".to_string(),
+        previous_sha: None,
+        new_sha: None,
+        commit_count: None,
+        commits: Vec::new(),
+        diff: DiffStat::default(),
+        steps: Vec::new(),
+        health: None,
+        message: None,
+        error: None,
+        created_at: Utc::now(),
+        started_at: None,
+        finished_at: None,
+        duration_ms: None,
+        commands: Vec::new(),
+    }
+}
@@ -0,0 +1,834 @@
+use actix_web::http::header::HeaderMap;
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use hmac::{Hmac, KeyInit, Mac};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::Sha256;
+
+use crate::error::Error;
+
+pub const SIGNATURE_HEADER: &str = "X-Hub-Signature-256";
+
+/// The forge sending a target's push webhooks, which decides how they are authenticated and read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    /// HMAC-SHA256 of the body in `X-Hub-Signature-256`.
+    Github,
+    /// The secret itself in `X-Gitlab-Token`.
+    Gitlab,
+    /// Gitea or Forgejo: hex HMAC-SHA256 of the body in `X-Gitea-Signature`.
+    #[serde(alias = "forgejo")]
+    #[value(alias = "forgejo")]
+    Gitea,
+    /// Bitbucket Cloud: HMAC-SHA256 of the body in `X-Hub-Signature`.
+    Bitbucket,
+}
+
+/// What a verified webhook asks for.
+#[derive(Debug)]
+pub enum Hook {
+    /// GitHub checking that the webhook works.
+    Ping,
+    Push(PushEvent),
+    /// A GitHub Actions workflow finished successfully on `branch`; deploy what it tested.
+    WorkflowRun {
+        workflow: String,
+        /// Workflow file, e.g. `.github/workflows/quality.yml`.
+        path: String,
+        branch: String,
+        sha: String,
+        actor: String,
+    },
+    /// A GitHub release was published; deploy its tag.
+    Release { tag: String, prerelease: bool, author: String },
+    /// An event that does not call for a deploy, with the reason.
+    Ignored(String),
+}
+
+/// A push, whichever forge reported it.
+#[derive(Debug)]
+pub struct PushEvent {
+    pub git_ref: String,
+    /// Commit the ref pointed at before the push; all zeros for a new ref.
+    pub before: String,
+    /// Commit the ref points at now; all zeros for a deleted ref.
+    pub after: String,
+    pub commits: Vec<PushCommit>,
+    pub pusher: String,
+    /// `owner/name` of the pushed repository.
+    pub repository: String,
+}
+
+#[derive(Debug)]
+pub struct PushCommit {
+    pub id: String,
+    pub message: String,
+    pub author: String,
+}
+
+impl PushEvent {
+    /// Branch name for `refs/heads/*` refs, `None` for tags and other refs.
+    pub fn branch(&self) -> Option<&str> {
+        self.git_ref.strip_prefix("refs/heads/")
+    }
+}
+
+const ZERO_SHA: &str = "0000000000000000000000000000000000000000";
+
+impl Provider {
+    pub fn name(self) -> &'static str {
+        match self {
+            Provider::Github => "github",
+            Provider::Gitlab => "gitlab",
+            Provider::Gitea => "gitea",
+            Provider::Bitbucket => "bitbucket",
+        }
+    }
+
+    /// Header carrying the provider's proof that the request knows the secret.
+    pub fn auth_header(self) -> &'static str {
+        match self {
+            Provider::Github => SIGNATURE_HEADER,
+            Provider::Gitlab => "X-Gitlab-Token",
+            Provider::Gitea => "X-Gitea-Signature",
+            Provider::Bitbucket => "X-Hub-Signature",
+        }
+    }
+
+    /// Header naming the kind of event.
+    fn event_header(self) -> &'static str {
+        match self {
+            Provider::Github => "X-GitHub-Event",
+            Provider::Gitlab => "X-Gitlab-Event",
+            Provider::Gitea => "X-Gitea-Event",
+            Provider::Bitbucket => "X-Event-Key",
+        }
+    }
+
+    /// Header carrying the ID of a delivery, which a redelivery of it keeps.
+    pub fn delivery_header(self) -> &'static str {
+        match self {
+            Provider::Github => "X-GitHub-Delivery",
+            Provider::Gitlab => "X-Gitlab-Event-UUID",
+            Provider::Gitea => "X-Gitea-Delivery",
+            Provider::Bitbucket => "X-Request-UUID",
+        }
+    }
+
+    fn push_event(self) -> &'static str {
+        match self {
+            Provider::Github | Provider::Gitea => "push",
+            Provider::Gitlab => "Push Hook",
+            Provider::Bitbucket => "repo:push",
+        }
+    }
+
+    pub fn verify(self, secret: &[u8], headers: &HeaderMap, body: &[u8]) -> bool {
+        let header = headers.get(self.auth_header()).and_then(|value| value.to_str().ok());
+        match self {
+            Provider::Github | Provider::Bitbucket => verify_signature(secret, body, header),
+            Provider::Gitlab => header.is_some_and(|token| constant_time_eq(token.as_bytes(), secret)),
+            Provider::Gitea => header
+                .and_then(|digest| hex::decode(digest).ok())
+                .is_some_and(|expected| hmac(secret, body).verify_slice(&expected).is_ok()),
+        }
+    }
+
+    /// Reads a webhook by the kind in its event header. Requests without the header, such as
+    /// hand-made curls, count as pushes.
+    pub fn parse(self, headers: &HeaderMap, body: &[u8], branch: &str) -> Result<Hook, Error> {
+        let event = headers
+            .get(self.event_header())
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or(self.push_event());
+        let invalid = |err: serde_json::Error| Error::InvalidPayload(err.to_string());
+        match (self, event) {
+            (_, event) if event == self.push_event() => self.parse_push(body, branch).map(Hook::Push),
+            (Provider::Github, "ping") => Ok(Hook::Ping),
+            (Provider::Github, "workflow_run") => {
+                serde_json::from_slice::<WorkflowRunEvent>(body).map(Hook::from).map_err(invalid)
+            }
+            (Provider::Github, "release") => {
+                serde_json::from_slice::<ReleaseEvent>(body).map(Hook::from).map_err(invalid)
+            }
+            (_, event) => Ok(Hook::Ignored(format!("{} events are not deployed", event))),
+        }
+    }
+
+    /// The delivery ID, if the request has one.
+    pub fn delivery_id(self, headers: &HeaderMap) -> Option<&str> {
+        headers
+            .get(self.delivery_header())
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| !id.is_empty())
+    }
+
+    /// When the event happened, for the GitHub events whose payload says so: a push's
+    /// `repository.pushed_at`, a workflow run's `updated_at` and a release's `published_at`.
+    pub fn sent_at(self, headers: &HeaderMap, body: &[u8]) -> Option<DateTime<Utc>> {
+        if self != Provider::Github {
+            return None;
+        }
+        let event = headers
+            .get(self.event_header())
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or(self.push_event());
+        let pointer = match event {
+            "push" => "/repository/pushed_at",
+            "workflow_run" => "/workflow_run/updated_at",
+            "release" => "/release/published_at",
+            _ => return None,
+        };
+        let payload: Value = serde_json::from_slice(body).ok()?;
+        // Push payloads carry a Unix time, the others an ISO 8601 string.
+        match payload.pointer(pointer)? {
+            Value::Number(seconds) => DateTime::from_timestamp(seconds.as_i64()?, 0),
+            Value::String(time) => DateTime::parse_from_rfc3339(time).ok().map(|time| time.with_timezone(&Utc)),
+            _ => None,
+        }
+    }
+
+    /// Reads a push payload. Bitbucket reports several refs in one push; the change to `branch`
+    /// is picked if there is one.
+    fn parse_push(self, body: &[u8], branch: &str) -> Result<PushEvent, Error> {
+        let invalid = |err: serde_json::Error| Error::InvalidPayload(err.to_string());
+        match self {
+            Provider::Github => serde_json::from_slice::<GitHubPush>(body).map(PushEvent::from).map_err(invalid),
+            Provider::Gitlab => serde_json::from_slice::<GitLabPush>(body).map(PushEvent::from).map_err(invalid),
+            Provider::Gitea => serde_json::from_slice::<GiteaPush>(body).map(PushEvent::from).map_err(invalid),
+            Provider::Bitbucket => serde_json::from_slice::<BitbucketPush>(body)
+                .map_err(invalid)?
+                .into_event(branch),
+        }
+    }
+}
+
+/// Checks a GitHub `X-Hub-Signature-256` header (`sha256=<hex>`) against the raw request body.
+pub fn verify_signature(secret: &[u8], body: &[u8], header: Option<&str>) -> bool {
+    let Some(digest) = header.and_then(|value| value.strip_prefix("sha256=")) else {
+        return false;
+    };
+    let Ok(expected) = hex::decode(digest) else {
+        return false;
+    };
+    hmac(secret, body).verify_slice(&expected).is_ok()
+}
+
+fn hmac(secret: &[u8], body: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(body);
+    mac
+}
+
+/// Compares without returning early, so response times do not reveal how much of a token matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[derive(Deserialize)]
+struct GitHubPush {
+    #[serde(rename = "ref")]
+    git_ref: String,
+    #[serde(default)]
+    before: String,
+    after: String,
+    #[serde(default)]
+    commits: Vec<GitCommit>,
+    pusher: GitHubPusher,
+    repository: FullName,
+}
+
+#[derive(Deserialize)]
+struct GitHubPusher {
+    name: String,
+}
+
+/// A commit as GitHub, GitLab and Gitea list them in push payloads.
+#[derive(Deserialize)]
+struct GitCommit {
+    id: String,
+    message: String,
+    author: CommitAuthor,
+}
+
+#[derive(Deserialize)]
+struct CommitAuthor {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct FullName {
+    full_name: String,
+}
+
+impl From<GitCommit> for PushCommit {
+    fn from(commit: GitCommit) -> Self {
+        PushCommit {
+            id: commit.id,
+            message: commit.message,
+            author: commit.author.name,
+        }
+    }
+}
+
+impl From<GitHubPush> for PushEvent {
+    fn from(push: GitHubPush) -> Self {
+        PushEvent {
+            git_ref: push.git_ref,
+            before: push.before,
+            after: push.after,
+            commits: push.commits.into_iter().map(PushCommit::from).collect(),
+            pusher: push.pusher.name,
+            repository: push.repository.full_name,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct WorkflowRunEvent {
+    action: String,
+    workflow_run: WorkflowRun,
+    sender: Login,
+}
+
+#[derive(Deserialize)]
+struct WorkflowRun {
+    name: String,
+    path: String,
+    head_branch: String,
+    head_sha: String,
+    /// Unset until the run completes.
+    conclusion: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Login {
+    login: String,
+}
+
+impl From<WorkflowRunEvent> for Hook {
+    fn from(event: WorkflowRunEvent) -> Self {
+        let run = event.workflow_run;
+        let conclusion = run.conclusion.as_deref().unwrap_or("none");
+        if event.action != "completed" || conclusion != "success" {
+            return Hook::Ignored(format!(
+                "workflow run {} is {} with conclusion {}",
+                run.name, event.action, conclusion
+            ));
+        }
+        Hook::WorkflowRun {
+            workflow: run.name,
+            path: run.path,
+            branch: run.head_branch,
+            sha: run.head_sha,
+            actor: event.sender.login,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ReleaseEvent {
+    action: String,
+    release: Release,
+    sender: Login,
+}
+
+#[derive(Deserialize)]
+struct Release {
+    tag_name: String,
+    #[serde(default)]
+    prerelease: bool,
+}
+
+impl From<ReleaseEvent> for Hook {
+    fn from(event: ReleaseEvent) -> Self {
+        if event.action != "published" {
+            return Hook::Ignored(format!("release {} was {}", event.release.tag_name, event.action));
+        }
+        Hook::Release {
+            tag: event.release.tag_name,
+            prerelease: event.release.prerelease,
+            author: event.sender.login,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct GitLabPush {
+    #[serde(rename = "ref")]
+    git_ref: String,
+    before: String,
+    after: String,
+    #[serde(default)]
+    commits: Vec<GitCommit>,
+    user_username: String,
+    project: GitLabProject,
+}
+
+#[derive(Deserialize)]
+struct GitLabProject {
+    path_with_namespace: String,
+}
+
+impl From<GitLabPush> for PushEvent {
+    fn from(push: GitLabPush) -> Self {
+        PushEvent {
+            git_ref: push.git_ref,
+            before: push.before,
+            after: push.after,
+            commits: push.commits.into_iter().map(PushCommit::from).collect(),
+            pusher: push.user_username,
+            repository: push.project.path_with_namespace,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct GiteaPush {
+    #[serde(rename = "ref")]
+    git_ref: String,
+    before: String,
+    after: String,
+    #[serde(default)]
+    commits: Vec<GitCommit>,
+    pusher: GiteaUser,
+    repository: FullName,
+}
+
+#[derive(Deserialize)]
+struct GiteaUser {
+    /// Sent by Gitea and Forgejo alike.
+    login: String,
+}
+
+impl From<GiteaPush> for PushEvent {
+    fn from(push: GiteaPush) -> Self {
+        PushEvent {
+            git_ref: push.git_ref,
+            before: push.before,
+            after: push.after,
+            commits: push.commits.into_iter().map(PushCommit::from).collect(),
+            pusher: push.pusher.login,
+            repository: push.repository.full_name,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct BitbucketPush {
+    push: BitbucketChanges,
+    actor: BitbucketActor,
+    repository: FullName,
+}
+
+#[derive(Deserialize)]
+struct BitbucketChanges {
+    changes: Vec<BitbucketChange>,
+}
+
+/// One ref update. `old` is null for a new ref and `new` is null for a deleted one.
+#[derive(Deserialize)]
+struct BitbucketChange {
+    old: Option<BitbucketRef>,
+    new: Option<BitbucketRef>,
+    #[serde(default)]
+    commits: Vec<BitbucketCommit>,
+}
+
+#[derive(Deserialize)]
+struct BitbucketRef {
+    /// `branch` or `tag`.
+    #[serde(rename = "type")]
+    kind: String,
+    name: String,
+    target: BitbucketTarget,
+}
+
+#[derive(Deserialize)]
+struct BitbucketTarget {
+    hash: String,
+}
+
+#[derive(Deserialize)]
+struct BitbucketCommit {
+    hash: String,
+    message: String,
+    author: BitbucketAuthor,
+}
+
+/// `raw` is the git author line, `Name <email>`.
+#[derive(Deserialize)]
+struct BitbucketAuthor {
+    raw: String,
+}
+
+#[derive(Deserialize)]
+struct BitbucketActor {
+    display_name: String,
+}
+
+impl BitbucketRef {
+    fn git_ref(&self) -> String {
+        match self.kind.as_str() {
+            "tag" => format!("refs/tags/{}", self.name),
+            _ => format!("refs/heads/{}", self.name),
+        }
+    }
+}
+
+impl BitbucketPush {
+    fn into_event(self, branch: &str) -> Result<PushEvent, Error> {
+        let mut changes = self.push.changes;
+        if changes.is_empty() {
+            return Err(Error::InvalidPayload("push.changes is empty".to_string()));
+        }
+        let index = changes
+            .iter()
+            .position(|change| {
+                change
+                    .new
+                    .as_ref()
+                    .or(change.old.as_ref())
+                    .is_some_and(|reference| reference.kind == "branch" && reference.name == branch)
+            })
+            .unwrap_or(0);
+        let change = changes.swap_remove(index);
+        let git_ref = match change.new.as_ref().or(change.old.as_ref()) {
+            Some(reference) => reference.git_ref(),
+            None => return Err(Error::InvalidPayload("push change has neither old nor new".to_string())),
+        };
+        let sha = |reference: Option<BitbucketRef>| {
+            reference.map_or_else(|| ZERO_SHA.to_string(), |reference| reference.target.hash)
+        };
+        Ok(PushEvent {
+            git_ref,
+            before: sha(change.old),
+            after: sha(change.new),
+            commits: change
+                .commits
+                .into_iter()
+                .map(|commit| PushCommit {
+                    id: commit.hash,
+                    message: commit.message,
+                    author: commit
+                        .author
+                        .raw
+                        .split(" <")
+                        .next()
+                        .unwrap_or_default()
+                        .to_string(),
+                })
+                .collect(),
+            pusher: self.actor.display_name,
+            repository: self.repository.full_name,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::{HeaderName, HeaderValue};
+    use serde_json::json;
+
+    use super::*;
+
+    const SECRET: &[u8] = b"s3cret";
+    const BEFORE: &str = "1111111111111111111111111111111111111111";
+    const AFTER: &str = "2222222222222222222222222222222222222222";
+
+    fn sign(body: &[u8]) -> String {
+        hex::encode(hmac(SECRET, body).finalize().into_bytes())
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(HeaderName::from_bytes(name.as_bytes()).unwrap(), HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn push(provider: Provider, payload: Value, branch: &str) -> PushEvent {
+        match provider.parse(&HeaderMap::new(), payload.to_string().as_bytes(), branch) {
+            Ok(Hook::Push(push)) => push,
+            other => panic!("Not a push: {:?}", other),
+        }
+    }
+
+    fn github(event: &str, payload: Value) -> Hook {
+        Provider::Github
+            .parse(&headers(&[("X-GitHub-Event", event)]), payload.to_string().as_bytes(), "main")
+            .unwrap()
+    }
+
+    fn workflow_run(action: &str, conclusion: Option<&str>) -> Value {
+        json!({
+            "action": action,
+            "workflow_run": {
+                "name": "Code Quality",
+                "path": ".github/workflows/quality.yml",
+                "head_branch": "main",
+                "head_sha": AFTER,
+                "conclusion": conclusion,
+                "updated_at": "2024-05-01T12:00:00Z",
+            },
+            "sender": {"login": "mona"},
+        })
+    }
+
+    fn bitbucket_change(kind: &str, name: &str, old: Option<&str>, new: Option<&str>) -> Value {
+        let reference =
+            |hash: Option<&str>| hash.map(|hash| json!({"type": kind, "name": name, "target": {"hash": hash}}));
+        json!({
+            "old": reference(old),
+            "new": reference(new),
+            "commits": [{
+                "hash": new.unwrap_or(BEFORE),
+                "message": "Tweak\n",
+                "author": {"raw": "Mona <mona@example.com>"},
+            }],
+        })
+    }
+
+    fn bitbucket(changes: Vec<Value>) -> Value {
+        json!({
+            "push": {"changes": changes},
+            "actor": {"display_name": "Mona"},
+            "repository": {"full_name": "octo/web"},
+        })
+    }
+
+    #[test]
+    fn accepts_the_right_signature() {
+        let body = br#"{"ref":"refs/heads/main"}"#;
+        assert!(verify_signature(SECRET, body, Some(&format!("sha256={}", sign(body)))));
+    }
+
+    #[test]
+    fn rejects_bad_signatures() {
+        let body = br#"{"ref":"refs/heads/main"}"#;
+        let signature = sign(body);
+        assert!(!verify_signature(b"other", body, Some(&format!("sha256={}", signature))));
+        assert!(!verify_signature(SECRET, b"{}", Some(&format!("sha256={}", signature))));
+        assert!(!verify_signature(SECRET, body, Some(&signature)));
+        assert!(!verify_signature(SECRET, body, Some(&format!("sha1={}", signature))));
+        assert!(!verify_signature(SECRET, body, Some("sha256=not-hex")));
+        assert!(!verify_signature(SECRET, body, Some(&format!("sha256={}", &signature[..32]))));
+        assert!(!verify_signature(SECRET, body, None));
+    }
+
+    #[test]
+    fn verifies_each_provider() {
+        let body = b"{}";
+        let signature = sign(body);
+        let github = format!("sha256={}", signature);
+        let cases = [
+            (Provider::Github, "X-Hub-Signature-256", github.as_str()),
+            (Provider::Bitbucket, "X-Hub-Signature", github.as_str()),
+            (Provider::Gitea, "X-Gitea-Signature", signature.as_str()),
+            (Provider::Gitlab, "X-Gitlab-Token", "s3cret"),
+        ];
+        for (provider, header, good) in cases {
+            assert!(provider.verify(SECRET, &headers(&[(header, good)]), body), "{:?}", provider);
+            assert!(!provider.verify(b"other", &headers(&[(header, good)]), body), "{:?}", provider);
+            // GitLab sends the secret itself, which proves nothing about the body.
+            if provider != Provider::Gitlab {
+                assert!(!provider.verify(SECRET, &headers(&[(header, good)]), b"[]"), "{:?}", provider);
+            }
+            assert!(!provider.verify(SECRET, &HeaderMap::new(), body), "{:?}", provider);
+        }
+        // Each provider reads only its own header.
+        assert!(!Provider::Github.verify(SECRET, &headers(&[("X-Hub-Signature", &github)]), body));
+        assert!(!Provider::Gitea.verify(SECRET, &headers(&[("X-Gitea-Signature", &github)]), body));
+        assert!(!Provider::Gitlab.verify(SECRET, &headers(&[("X-Gitlab-Token", "s3cre")]), body));
+    }
+
+    #[test]
+    fn reads_github_pushes() {
+        let push = push(
+            Provider::Github,
+            json!({
+                "ref": "refs/heads/main",
+                "before": BEFORE,
+                "after": AFTER,
+                "commits": [{"id": AFTER, "message": "Fix", "author": {"name": "Mona", "email": "mona@example.com"}}],
+                "pusher": {"name": "mona", "email": "mona@example.com"},
+                "repository": {"full_name": "octo/web", "pushed_at": 1700000000},
+            }),
+            "main",
+        );
+        assert_eq!(push.branch(), Some("main"));
+        assert_eq!((push.before.as_str(), push.after.as_str()), (BEFORE, AFTER));
+        assert_eq!(push.pusher, "mona");
+        assert_eq!(push.repository, "octo/web");
+        assert_eq!(push.commits[0].author, "Mona");
+    }
+
+    #[test]
+    fn reads_gitlab_pushes() {
+        let push = push(
+            Provider::Gitlab,
+            json!({
+                "object_kind": "push",
+                "ref": "refs/heads/main",
+                "before": BEFORE,
+                "after": AFTER,
+                "user_username": "mona",
+                "project": {"path_with_namespace": "octo/web"},
+                "commits": [{"id": AFTER, "message": "Fix", "author": {"name": "Mona", "email": "mona@example.com"}}],
+            }),
+            "main",
+        );
+        assert_eq!(push.branch(), Some("main"));
+        assert_eq!(push.after, AFTER);
+        assert_eq!(push.pusher, "mona");
+        assert_eq!(push.repository, "octo/web");
+        assert_eq!(push.commits.len(), 1);
+    }
+
+    #[test]
+    fn reads_gitea_pushes() {
+        let push = push(
+            Provider::Gitea,
+            json!({
+                "ref": "refs/tags/v1.0.0",
+                "before": ZERO_SHA,
+                "after": AFTER,
+                "commits": [],
+                "pusher": {"login": "mona", "username": "mona"},
+                "repository": {"full_name": "octo/web"},
+            }),
+            "main",
+        );
+        assert_eq!(push.branch(), None);
+        assert_eq!(push.before, ZERO_SHA);
+        assert_eq!(push.pusher, "mona");
+    }
+
+    #[test]
+    fn picks_the_bitbucket_change_to_the_branch() {
+        let push = push(
+            Provider::Bitbucket,
+            bitbucket(vec![
+                bitbucket_change("tag", "main", None, Some(BEFORE)),
+                bitbucket_change("branch", "feature", Some(BEFORE), Some(BEFORE)),
+                bitbucket_change("branch", "main", Some(BEFORE), Some(AFTER)),
+            ]),
+            "main",
+        );
+        assert_eq!(push.git_ref, "refs/heads/main");
+        assert_eq!((push.before.as_str(), push.after.as_str()), (BEFORE, AFTER));
+        assert_eq!(push.pusher, "Mona");
+        assert_eq!(push.repository, "octo/web");
+        assert_eq!(push.commits[0].author, "Mona");
+    }
+
+    #[test]
+    fn reads_bitbucket_deletions_and_other_branches() {
+        let deleted = push(
+            Provider::Bitbucket,
+            bitbucket(vec![bitbucket_change("branch", "main", Some(BEFORE), None)]),
+            "main",
+        );
+        assert_eq!((deleted.git_ref.as_str(), deleted.after.as_str()), ("refs/heads/main", ZERO_SHA));
+        let tag = push(
+            Provider::Bitbucket,
+            bitbucket(vec![bitbucket_change("tag", "v1.0.0", None, Some(AFTER))]),
+            "main",
+        );
+        assert_eq!((tag.git_ref.as_str(), tag.before.as_str()), ("refs/tags/v1.0.0", ZERO_SHA));
+        assert!(matches!(
+            Provider::Bitbucket.parse(&HeaderMap::new(), bitbucket(Vec::new()).to_string().as_bytes(), "main"),
+            Err(Error::InvalidPayload(_))
+        ));
+    }
+
+    #[test]
+    fn ignores_events_other_than_pushes() {
+        let hook = Provider::Gitlab.parse(&headers(&[("X-Gitlab-Event", "Tag Push Hook")]), b"{}", "main");
+        assert!(matches!(hook, Ok(Hook::Ignored(_))));
+        let hook = Provider::Bitbucket.parse(&headers(&[("X-Event-Key", "pullrequest:created")]), b"{}", "main");
+        assert!(matches!(hook, Ok(Hook::Ignored(_))));
+        let hook = Provider::Gitea.parse(&headers(&[("X-Gitea-Event", "push")]), b"{", "main");
+        assert!(matches!(hook, Err(Error::InvalidPayload(_))));
+    }
+
+    #[test]
+    fn reads_pings() {
+        assert!(matches!(github("ping", json!({"zen": "Keep it logically awesome."})), Hook::Ping));
+    }
+
+    #[test]
+    fn reads_successful_workflow_runs() {
+        match github("workflow_run", workflow_run("completed", Some("success"))) {
+            Hook::WorkflowRun {
+                workflow,
+                path,
+                branch,
+                sha,
+                actor,
+            } => {
+                assert_eq!(workflow, "Code Quality");
+                assert_eq!(path, ".github/workflows/quality.yml");
+                assert_eq!((branch.as_str(), sha.as_str(), actor.as_str()), ("main", AFTER, "mona"));
+            }
+            other => panic!("Not a workflow run: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn ignores_unfinished_and_failed_workflow_runs() {
+        let hook = github("workflow_run", workflow_run("requested", None));
+        assert!(matches!(hook, Hook::Ignored(_)), "{:?}", hook);
+        let hook = github("workflow_run", workflow_run("completed", Some("failure")));
+        assert!(matches!(hook, Hook::Ignored(_)), "{:?}", hook);
+    }
+
+    #[test]
+    fn reads_published_releases() {
+        let release = |action: &str| {
+            json!({
+                "action": action,
+                "release": {"tag_name": "v1.2.0", "published_at": "2024-05-01T12:00:00Z"},
+                "sender": {"login": "mona"},
+            })
+        };
+        match github("release", release("published")) {
+            Hook::Release {
+                tag,
+                prerelease,
+                author,
+            } => assert_eq!((tag.as_str(), prerelease, author.as_str()), ("v1.2.0", false, "mona")),
+            other => panic!("Not a release: {:?}", other),
+        }
+        let mut prerelease = release("published");
+        prerelease["release"]["prerelease"] = Value::from(true);
+        assert!(matches!(github("release", prerelease), Hook::Release { prerelease: true, .. }));
+        assert!(matches!(github("release", release("created")), Hook::Ignored(_)));
+        assert!(matches!(github("issues", json!({})), Hook::Ignored(_)));
+    }
+
+    #[test]
+    fn reads_delivery_ids() {
+        let github = headers(&[("X-GitHub-Delivery", "72d3162e"), ("X-Request-UUID", "")]);
+        assert_eq!(Provider::Github.delivery_id(&github), Some("72d3162e"));
+        assert_eq!(Provider::Gitea.delivery_id(&github), None);
+        assert_eq!(Provider::Bitbucket.delivery_id(&github), None);
+        let gitlab = headers(&[("X-Gitlab-Event-UUID", "9b1c")]);
+        assert_eq!(Provider::Gitlab.delivery_id(&gitlab), Some("9b1c"));
+    }
+
+    #[test]
+    fn reads_github_send_times() {
+        let pushed = json!({"repository": {"pushed_at": 1714564800}}).to_string();
+        let expected = DateTime::parse_from_rfc3339("2024-05-01T12:00:00Z").unwrap();
+        assert_eq!(Provider::Github.sent_at(&HeaderMap::new(), pushed.as_bytes()), Some(expected.into()));
+        let run = workflow_run("completed", Some("success")).to_string();
+        let headers = headers(&[("X-GitHub-Event", "workflow_run")]);
+        assert_eq!(Provider::Github.sent_at(&headers, run.as_bytes()), Some(expected.into()));
+        assert_eq!(Provider::Github.sent_at(&headers, b"{}"), None);
+        assert_eq!(Provider::Gitea.sent_at(&HeaderMap::new(), pushed.as_bytes()), None);
+    }
+}